@@ -213,6 +213,16 @@ fn criterion_benchmark(c: &mut Criterion) {
             .collect::<Vec<_>>(),
     );
 
+    #[cfg(feature = "rayon")]
+    c.bench_function("concurrent_creation_par_intern", |b| {
+        use rayon::prelude::*;
+        let data = Arc::clone(&test_data);
+        b.iter(|| {
+            unsafe { ustr::_clear_cache() };
+            black_box(par_intern(data.par_iter().map(String::as_str)));
+        });
+    });
+
     c.bench_function("concurrent_hash_collisions", |b| {
         let data = Arc::clone(&collision_data);
         let num_threads = 4;
@@ -42,6 +42,16 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     });
 
+    let s = raft.clone();
+    c.bench_function("single raft ustr intern_many", move |b| {
+        b.iter(|| {
+            unsafe { ustr::_clear_cache() };
+            black_box(ustr::intern_many(
+                s.iter().cycle().take(100_000).map(String::as_str),
+            ));
+        });
+    });
+
     let s = raft.clone();
     c.bench_function("single raft string-interner", move |b| {
         b.iter(|| {
@@ -183,6 +183,17 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     });
 
+    // Benchmark Ustr creation with a thread-local front cache
+    ustr::set_front_cache_size(1024);
+    c.bench_function("ustr_creation_front_cache", |b| {
+        b.iter(|| {
+            for &s in &test_strings {
+                black_box(ustr(s));
+            }
+        });
+    });
+    ustr::set_front_cache_size(0);
+
     // Benchmark Ustr as_str conversion
     c.bench_function("ustr_as_str", |b| {
         b.iter(|| {
@@ -201,6 +212,43 @@ fn criterion_benchmark(c: &mut Criterion) {
         });
     });
 
+    // Benchmark lookups in a well-populated cache, where probing has to get
+    // past other entries. Hits are strings that are in the cache, misses
+    // strings that are not.
+    let populated: Vec<String> =
+        (0..500_000).map(|i| format!("populated {i}")).collect();
+    for s in &populated {
+        ustr(s);
+    }
+    let hits: Vec<&str> =
+        populated.iter().step_by(500).map(String::as_str).collect();
+    let misses: Vec<String> =
+        (0..hits.len()).map(|i| format!("missing {i}")).collect();
+
+    c.bench_function("existing_ustr_hit_populated", |b| {
+        b.iter(|| {
+            for &s in &hits {
+                black_box(existing_ustr(s));
+            }
+        });
+    });
+
+    c.bench_function("existing_ustr_miss_populated", |b| {
+        b.iter(|| {
+            for s in &misses {
+                black_box(existing_ustr(s));
+            }
+        });
+    });
+
+    c.bench_function("ustr_hit_populated", |b| {
+        b.iter(|| {
+            for &s in &hits {
+                black_box(ustr(s));
+            }
+        });
+    });
+
     // Benchmark Ustr in HashSet
     c.bench_function("ustr_hashset_insert", |b| {
         b.iter(|| {
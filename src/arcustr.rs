@@ -0,0 +1,364 @@
+use crate::{
+    InternError, STRING_CACHE, Ustr,
+    stringcache::{RcEntry, StringCacheEntry, is_immortal},
+};
+use alloc::string::String;
+use core::{
+    cmp::Ordering,
+    ffi::{CStr, c_char},
+    fmt,
+    hash::{Hash, Hasher},
+    ops::Deref,
+    ptr::NonNull,
+    slice, str,
+    sync::atomic::{self, AtomicUsize},
+};
+
+// Set on `ArcUstr::char_ptr` when the handle owns a reference. The chars always
+// follow an 8-byte aligned header, so the low bit is free.
+const COUNTED_TAG: usize = 1;
+
+/// A reference-counted handle to a string in the global string cache.
+///
+/// `ArcUstr` is the collectable flavor of [`Ustr`]: once the last handle to a
+/// string is dropped, the string is removed from the cache and its memory is
+/// freed. This makes it suitable for untrusted input, such as user-supplied tag
+/// names, which would otherwise grow the cache without bound.
+///
+/// Both flavors share the same cache, so comparing an `ArcUstr` with a `Ustr`
+/// for the same string is cheap. If a `Ustr` is ever created for a string that
+/// is held by `ArcUstr`s, the string becomes immortal and is never freed, and
+/// an `ArcUstr` created for a string that already has a `Ustr` just refers to
+/// the immortal entry.
+///
+/// Cloning and dropping an `ArcUstr` costs an atomic operation, and dropping
+/// the last one takes the lock of the string's bin.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::ArcUstr;
+/// # unsafe { ustr::_clear_cache() };
+///
+/// let a1 = ArcUstr::from("user supplied");
+/// let a2 = a1.clone();
+/// assert_eq!(a1, a2);
+/// assert_eq!(ustr::num_entries(), 1);
+///
+/// drop(a1);
+/// drop(a2);
+/// assert_eq!(ustr::num_entries(), 0);
+/// assert_eq!(ArcUstr::from_existing("user supplied"), None);
+/// ```
+#[repr(transparent)]
+pub struct ArcUstr {
+    char_ptr: NonNull<u8>,
+}
+
+impl ArcUstr {
+    /// Create a new `ArcUstr` from the given `str`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be added to the cache, see
+    /// [`ArcUstr::try_from_str`].
+    pub fn from(string: &str) -> ArcUstr {
+        ArcUstr::try_from_str(string)
+            .unwrap_or_else(|e| panic!("failed to intern string: {e}"))
+    }
+
+    /// Create a new `ArcUstr` from the given `str`, or return an error if the
+    /// string is not in the cache yet and can't be added, e.g. because the
+    /// [`Budget`](crate::Budget) is exhausted.
+    pub fn try_from_str(string: &str) -> Result<ArcUstr, InternError> {
+        let hash = crate::hash::hash(string.as_bytes());
+        #[cfg(all(feature = "dylib", target_os = "linux"))]
+        if let Some(owner) = crate::dylib::owner() {
+            let (ptr, counted) = owner.intern_counted(string, hash)?;
+            return Ok(unsafe { ArcUstr::from_raw(ptr, counted) });
+        }
+        let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+        let (ptr, counted) = sc.try_insert_counted(string, hash)?;
+        // SAFETY: `try_insert_counted` does not give back a null pointer and
+        // the count was incremented for us.
+        Ok(unsafe { ArcUstr::from_raw(ptr, counted) })
+    }
+
+    /// Create a new `ArcUstr` from the given `str` but only if it already
+    /// exists in the string cache.
+    pub fn from_existing(string: &str) -> Option<ArcUstr> {
+        let hash = crate::hash::hash(string.as_bytes());
+        #[cfg(all(feature = "dylib", target_os = "linux"))]
+        if let Some(owner) = crate::dylib::owner() {
+            return owner.get_existing_counted(string, hash).map(
+                |(ptr, counted)| unsafe { ArcUstr::from_raw(ptr, counted) },
+            );
+        }
+        let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+        sc.get_existing_counted(string, hash)
+            .map(|(ptr, counted)| unsafe { ArcUstr::from_raw(ptr, counted) })
+    }
+
+    // This is safe as long as `ptr` points to the chars of a cache entry and,
+    // if `counted` is set, a reference to it was taken for this handle.
+    unsafe fn from_raw(ptr: *const u8, counted: bool) -> ArcUstr {
+        let ptr = if counted {
+            ptr.map_addr(|a| a | COUNTED_TAG)
+        } else {
+            ptr
+        };
+        ArcUstr {
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        }
+    }
+
+    #[inline]
+    fn is_counted(&self) -> bool {
+        self.char_ptr.addr().get() & COUNTED_TAG != 0
+    }
+
+    #[inline]
+    fn chars(&self) -> *mut u8 {
+        self.char_ptr.as_ptr().map_addr(|a| a & !COUNTED_TAG)
+    }
+
+    // The chars always directly follow the header, counted or not.
+    #[inline]
+    fn entry_ptr(&self) -> *mut StringCacheEntry {
+        unsafe { self.chars().cast::<StringCacheEntry>().sub(1) }
+    }
+
+    #[inline]
+    fn as_string_cache_entry(&self) -> &StringCacheEntry {
+        unsafe { &*self.entry_ptr() }
+    }
+
+    // Only valid if `is_counted()`.
+    #[inline]
+    fn refcount(&self) -> &AtomicUsize {
+        unsafe { &(*RcEntry::from_entry(self.entry_ptr())).refcount }
+    }
+
+    /// Get the cached string as a `str`.
+    pub fn as_str(&self) -> &str {
+        // This is safe for the same reasons as `Ustr::as_str()`, and the
+        // entry is kept alive by this handle.
+        unsafe {
+            str::from_utf8_unchecked(slice::from_raw_parts(
+                self.chars(),
+                self.len(),
+            ))
+        }
+    }
+
+    /// Get the cached string as a C `char*`.
+    ///
+    /// This includes the null terminator so is safe to pass straight to FFI,
+    /// as long as the `ArcUstr` outlives its use.
+    pub fn as_char_ptr(&self) -> *const c_char {
+        self.chars() as *const c_char
+    }
+
+    /// Get this `ArcUstr` as a [`CStr`].
+    pub fn as_cstr(&self) -> &CStr {
+        unsafe {
+            CStr::from_bytes_with_nul_unchecked(slice::from_raw_parts(
+                self.chars(),
+                self.len() + 1,
+            ))
+        }
+    }
+
+    /// Get the length (in bytes) of this string.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.as_string_cache_entry().len
+    }
+
+    /// Returns true if the length is zero.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the precomputed hash for this string.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.as_string_cache_entry().hash
+    }
+
+    /// Get an immortal [`Ustr`] for this string.
+    ///
+    /// This pins the string in the cache forever, just like creating the `Ustr`
+    /// with [`Ustr::from`] would.
+    pub fn to_ustr(&self) -> Ustr {
+        Ustr::from(self.as_str())
+    }
+}
+
+impl Clone for ArcUstr {
+    fn clone(&self) -> ArcUstr {
+        if self.is_counted() {
+            // We already hold a reference, so the count can't drop to zero
+            // under us.
+            self.refcount().fetch_add(1, atomic::Ordering::Relaxed);
+        }
+        ArcUstr {
+            char_ptr: self.char_ptr,
+        }
+    }
+}
+
+impl Drop for ArcUstr {
+    fn drop(&mut self) {
+        if !self.is_counted() {
+            return;
+        }
+
+        // Drop our reference without taking the lock as long as it's not the
+        // last one.
+        let refcount = self.refcount();
+        let mut current = refcount.load(atomic::Ordering::Relaxed);
+        loop {
+            if is_immortal(current) {
+                return;
+            }
+            if current == 1 {
+                break;
+            }
+            match refcount.compare_exchange_weak(
+                current,
+                current - 1,
+                atomic::Ordering::Release,
+                atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => return,
+                Err(actual) => current = actual,
+            }
+        }
+
+        // This may be the last reference, which only the copy of ustr owning
+        // the cache can drop.
+        #[cfg(all(feature = "dylib", target_os = "linux"))]
+        if let Some(owner) = crate::dylib::owner() {
+            unsafe { owner.release_counted(self.chars()) };
+            return;
+        }
+        unsafe { release_last(self.entry_ptr()) };
+    }
+}
+
+// Drop what may be the last reference to a counted entry. The final decrement
+// happens under the bin's lock so a concurrent lookup can't hand out a new
+// reference to an entry we're about to free.
+//
+// This is safe as long as `entry` is a counted entry of the global cache and
+// the caller gives up a reference to it.
+pub(crate) unsafe fn release_last(entry: *mut StringCacheEntry) {
+    let (hash, refcount) =
+        unsafe { ((*entry).hash, &(*RcEntry::from_entry(entry)).refcount) };
+    let mut sc = STRING_CACHE.0[STRING_CACHE.whichbin(hash)].lock();
+    if refcount.fetch_sub(1, atomic::Ordering::AcqRel) == 1 {
+        // SAFETY: the count dropped to zero under the lock, so this was the
+        // last handle and no new ones can be created.
+        unsafe { sc.remove_counted(entry) };
+    }
+}
+
+// The string is immutable and the reference count is atomic.
+unsafe impl Send for ArcUstr {}
+unsafe impl Sync for ArcUstr {}
+
+impl PartialEq for ArcUstr {
+    fn eq(&self, other: &Self) -> bool {
+        self.chars() == other.chars()
+    }
+}
+
+impl Eq for ArcUstr {}
+
+impl PartialEq<Ustr> for ArcUstr {
+    fn eq(&self, other: &Ustr) -> bool {
+        self.chars() as *const c_char == other.as_char_ptr()
+    }
+}
+
+impl PartialEq<ArcUstr> for Ustr {
+    fn eq(&self, other: &ArcUstr) -> bool {
+        other == self
+    }
+}
+
+impl PartialEq<str> for ArcUstr {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for ArcUstr {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+/// Defer to `str` for ordering, like `Ustr`.
+impl Ord for ArcUstr {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for ArcUstr {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, like `Ustr` does.
+impl Hash for ArcUstr {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+impl Deref for ArcUstr {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for ArcUstr {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl From<&str> for ArcUstr {
+    fn from(s: &str) -> ArcUstr {
+        ArcUstr::from(s)
+    }
+}
+
+impl From<String> for ArcUstr {
+    fn from(s: String) -> ArcUstr {
+        ArcUstr::from(s.as_str())
+    }
+}
+
+impl From<&ArcUstr> for Ustr {
+    fn from(s: &ArcUstr) -> Ustr {
+        s.to_ustr()
+    }
+}
+
+impl fmt::Display for ArcUstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for ArcUstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a!({:?})", self.as_str())
+    }
+}
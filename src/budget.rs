@@ -0,0 +1,174 @@
+use core::{
+    fmt,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+/// Limits on how much the global string cache may grow.
+///
+/// Once a limit is reached, interning a new string with
+/// [`Ustr::try_from_str`](crate::Ustr::try_from_str) returns an
+/// [`InternError`] instead of growing the cache, while strings that are
+/// already in the cache can still be looked up and interned as usual.
+/// [`Ustr::from`](crate::Ustr::from) panics in that case.
+///
+/// A limit of `None` means unlimited, which is the default.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{Budget, InternError, Ustr};
+/// # unsafe { ustr::_clear_cache() };
+///
+/// ustr::set_budget(Budget {
+///     max_entries: Some(1),
+///     max_string_len: Some(16),
+///     ..Budget::default()
+/// });
+///
+/// let hello = Ustr::try_from_str("hello").unwrap();
+/// assert_eq!(
+///     Ustr::try_from_str("world"),
+///     Err(InternError::TooManyEntries { max: 1 })
+/// );
+/// // Existing strings are still available.
+/// assert_eq!(Ustr::try_from_str("hello"), Ok(hello));
+/// ```
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Maximum number of bytes of string storage, including the header stored
+    /// with each string.
+    pub max_bytes: Option<usize>,
+    /// Maximum number of unique strings.
+    pub max_entries: Option<usize>,
+    /// Maximum length in bytes of a single string.
+    pub max_string_len: Option<usize>,
+}
+
+/// Error returned when a string can't be interned.
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum InternError {
+    /// The string is longer than [`Budget::max_string_len`] allows.
+    StringTooLong {
+        /// Length of the rejected string in bytes.
+        len: usize,
+        /// The configured maximum.
+        max: usize,
+    },
+    /// The cache already holds [`Budget::max_entries`] strings.
+    TooManyEntries {
+        /// The configured maximum.
+        max: usize,
+    },
+    /// Storing the string would exceed [`Budget::max_bytes`].
+    TooManyBytes {
+        /// The configured maximum.
+        max: usize,
+    },
+    /// The memory for the string or the cache's table could not be
+    /// allocated.
+    OutOfMemory,
+    /// The cache has been sealed with [`freeze()`](crate::freeze), so no new
+    /// strings can be added.
+    Frozen,
+}
+
+impl fmt::Display for InternError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InternError::StringTooLong { len, max } => write!(
+                f,
+                "string of {len} bytes exceeds the maximum length of {max}"
+            ),
+            InternError::TooManyEntries { max } => {
+                write!(f, "string cache is limited to {max} entries")
+            }
+            InternError::TooManyBytes { max } => {
+                write!(f, "string cache is limited to {max} bytes")
+            }
+            InternError::OutOfMemory => {
+                write!(f, "out of memory while interning string")
+            }
+            InternError::Frozen => {
+                write!(f, "string cache is frozen")
+            }
+        }
+    }
+}
+
+impl core::error::Error for InternError {}
+
+// Limits, with `usize::MAX` meaning unlimited.
+static MAX_BYTES: AtomicUsize = AtomicUsize::new(usize::MAX);
+static MAX_ENTRIES: AtomicUsize = AtomicUsize::new(usize::MAX);
+static MAX_STRING_LEN: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+// What the global cache currently uses, summed over all bins.
+static USED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static USED_ENTRIES: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the limits on the global string cache.
+///
+/// This can be called at any time. Lowering a limit below what the cache
+/// already uses doesn't remove anything, it just stops new strings from being
+/// added.
+pub fn set_budget(budget: Budget) {
+    MAX_BYTES.store(budget.max_bytes.unwrap_or(usize::MAX), Ordering::Relaxed);
+    MAX_ENTRIES
+        .store(budget.max_entries.unwrap_or(usize::MAX), Ordering::Relaxed);
+    MAX_STRING_LEN.store(
+        budget.max_string_len.unwrap_or(usize::MAX),
+        Ordering::Relaxed,
+    );
+}
+
+/// Returns the limits currently set on the global string cache.
+pub fn budget() -> Budget {
+    let limit = |max: &AtomicUsize| match max.load(Ordering::Relaxed) {
+        usize::MAX => None,
+        max => Some(max),
+    };
+    Budget {
+        max_bytes: limit(&MAX_BYTES),
+        max_entries: limit(&MAX_ENTRIES),
+        max_string_len: limit(&MAX_STRING_LEN),
+    }
+}
+
+// Reserve room for a new entry of a string of `len` bytes taking up `bytes`
+// bytes of storage, or return the limit that stops us.
+pub(crate) fn reserve(len: usize, bytes: usize) -> Result<(), InternError> {
+    let max = MAX_STRING_LEN.load(Ordering::Relaxed);
+    if len > max {
+        return Err(InternError::StringTooLong { len, max });
+    }
+
+    let max = MAX_ENTRIES.load(Ordering::Relaxed);
+    if USED_ENTRIES.fetch_add(1, Ordering::Relaxed) >= max {
+        USED_ENTRIES.fetch_sub(1, Ordering::Relaxed);
+        return Err(InternError::TooManyEntries { max });
+    }
+
+    let max = MAX_BYTES.load(Ordering::Relaxed);
+    let used = USED_BYTES.fetch_add(bytes, Ordering::Relaxed);
+    if used.saturating_add(bytes) > max {
+        USED_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+        USED_ENTRIES.fetch_sub(1, Ordering::Relaxed);
+        return Err(InternError::TooManyBytes { max });
+    }
+
+    Ok(())
+}
+
+// Give back what `reserve()` took, once an entry is removed or could not be
+// created after all.
+pub(crate) fn release(bytes: usize) {
+    USED_ENTRIES.fetch_sub(1, Ordering::Relaxed);
+    USED_BYTES.fetch_sub(bytes, Ordering::Relaxed);
+}
+
+// Called when the global cache is cleared.
+pub(crate) fn reset() {
+    USED_ENTRIES.store(0, Ordering::Relaxed);
+    USED_BYTES.store(0, Ordering::Relaxed);
+}
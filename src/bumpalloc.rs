@@ -1,4 +1,35 @@
-use std::alloc::{GlobalAlloc, Layout, System};
+use crate::budget::InternError;
+use alloc::alloc::Layout;
+#[cfg(feature = "std")]
+use std::alloc::{GlobalAlloc, System};
+
+// Without the standard library the system allocator can't be reached
+// directly, so arenas come from the global allocator.
+#[cfg(not(feature = "std"))]
+struct System;
+
+#[cfg(not(feature = "std"))]
+impl System {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        unsafe { alloc::alloc::alloc(layout) }
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        unsafe { alloc::alloc::dealloc(ptr, layout) }
+    }
+}
+
+// Abort rather than panic to avoid poisoning the cache mutex. Without the
+// standard library, the allocation error handler does the aborting.
+fn abort(layout: Layout) -> ! {
+    #[cfg(feature = "std")]
+    {
+        let _ = layout;
+        std::process::abort();
+    }
+    #[cfg(not(feature = "std"))]
+    alloc::alloc::handle_alloc_error(layout)
+}
 
 /// Simple, fast bump allocator specialized for the string cache.
 /// Bumps a pointer downward and aborts on exhaustion; callers are expected
@@ -10,51 +41,154 @@ pub(crate) struct LeakyBumpAlloc {
     start: *mut u8,
     end: *mut u8,
     ptr: *mut u8,
+    backing: Backing,
+    // Lowest address that can be written to. Everything from here to `end`
+    // is committed; for heap-backed allocators that's the whole region.
+    committed: *mut u8,
+}
+
+/// Where the memory of a `LeakyBumpAlloc` comes from.
+#[derive(Clone, Debug)]
+pub(crate) enum Backing {
+    /// A single heap allocation of the full capacity.
+    Heap,
+    /// A reserved range of address space, whose pages are committed as the
+    /// bump pointer reaches them, optionally backed by transparent huge pages.
+    #[cfg(all(feature = "mmap", unix))]
+    Mmap { huge_pages: bool },
+    /// A region of a persistent cache file holding the strings of one bin,
+    /// see `persist`.
+    #[cfg(all(feature = "mmap", unix))]
+    File {
+        file: std::sync::Arc<crate::persist::PersistentFile>,
+        bin: usize,
+    },
 }
 
 impl LeakyBumpAlloc {
-    pub fn new(capacity: usize, alignment: usize) -> LeakyBumpAlloc {
-        let layout = Layout::from_size_align(capacity, alignment)
-            .expect("invalid layout");
+    pub fn new(
+        capacity: usize,
+        alignment: usize,
+        backing: Backing,
+    ) -> LeakyBumpAlloc {
+        LeakyBumpAlloc::try_new(capacity, alignment, backing).unwrap_or_else(
+            || {
+                abort(
+                    Layout::from_size_align(capacity, alignment)
+                        .unwrap_or(Layout::new::<u8>()),
+                )
+            },
+        )
+    }
+
+    /// Like `new`, but returns `None` if the memory can't be allocated.
+    pub fn try_new(
+        capacity: usize,
+        alignment: usize,
+        backing: Backing,
+    ) -> Option<LeakyBumpAlloc> {
+        match backing {
+            Backing::Heap => LeakyBumpAlloc::try_heap(capacity, alignment),
+            #[cfg(all(feature = "mmap", unix))]
+            Backing::Mmap { huge_pages } => {
+                mmap::reserve(capacity, alignment, huge_pages)
+            }
+            #[cfg(all(feature = "mmap", unix))]
+            Backing::File { file, bin } => {
+                crate::persist::new_region(file, bin, capacity, alignment)
+            }
+        }
+    }
+
+    /// An allocator for the region from `start` to `end`, of which everything
+    /// from `ptr` up has already been handed out.
+    ///
+    /// # Safety
+    ///
+    /// The region must be valid for reads and writes, aligned to `alignment`
+    /// at both ends, and stay valid until `clear` is called.
+    #[cfg(all(feature = "mmap", unix))]
+    pub unsafe fn from_raw_parts(
+        start: *mut u8,
+        end: *mut u8,
+        ptr: *mut u8,
+        alignment: usize,
+        backing: Backing,
+    ) -> Option<LeakyBumpAlloc> {
+        let layout =
+            Layout::from_size_align(end as usize - start as usize, alignment)
+                .ok()?;
+        Some(LeakyBumpAlloc {
+            layout,
+            start,
+            end,
+            ptr,
+            backing,
+            committed: start,
+        })
+    }
+
+    fn try_heap(capacity: usize, alignment: usize) -> Option<LeakyBumpAlloc> {
+        let layout = Layout::from_size_align(capacity, alignment).ok()?;
         // SAFETY: `layout` is valid (non-zero size, power-of-two alignment)
         // since `from_size_align` succeeded. We check for null below.
         let start = unsafe { System.alloc(layout) };
         if start.is_null() {
-            // Abort rather than panic to avoid poisoning the cache mutex.
-            std::process::abort();
+            return None;
         }
         // SAFETY: `start` is non-null and points to an allocation of
         // `layout.size()` bytes, so `start + layout.size()` is one past
         // the end of that allocation, which is a valid pointer value.
         let end = unsafe { start.add(layout.size()) };
-        LeakyBumpAlloc {
+        Some(LeakyBumpAlloc {
             layout,
             start,
             end,
             ptr: end,
-        }
+            backing: Backing::Heap,
+            committed: start,
+        })
     }
 
     /// # Safety
     ///
     /// This deallocates the backing memory. Caller must ensure no references
     /// to memory handed out by `allocate` are still in use. Intended only for
-    /// benchmark cleanup.
+    /// benchmark cleanup and for caches that are dropped as a whole, like
+    /// `LocalInterner`.
     #[doc(hidden)]
     pub unsafe fn clear(&mut self) {
-        // SAFETY: `self.start` was allocated via `System.alloc` with
-        // `self.layout`, and the caller guarantees no outstanding references.
-        unsafe {
-            System.dealloc(self.start, self.layout);
+        match self.backing {
+            // SAFETY: `self.start` was allocated via `System.alloc` with
+            // `self.layout`, and the caller guarantees no outstanding
+            // references.
+            Backing::Heap => unsafe { System.dealloc(self.start, self.layout) },
+            // SAFETY: `self.start` was mapped with `self.layout.size()` bytes,
+            // and the caller guarantees no outstanding references.
+            #[cfg(all(feature = "mmap", unix))]
+            Backing::Mmap { .. } => unsafe { mmap::release(self) },
+            // SAFETY: the region was mapped by `persist::new_region()` or
+            // when opening the file, and the caller guarantees no outstanding
+            // references.
+            #[cfg(all(feature = "mmap", unix))]
+            Backing::File { .. } => unsafe {
+                crate::persist::unmap(self.start, self.capacity())
+            },
         }
     }
 
+    /// Returns `InternError::OutOfMemory` if the pages for the allocation
+    /// can't be committed, leaving the allocator as it was.
+    ///
     /// # Safety
     ///
     /// The returned pointer is valid for writes of `num_bytes` bytes and
     /// remains valid for the lifetime of the allocator (i.e., until `clear`
     /// is called). Caller must ensure proper initialization before reading.
-    pub unsafe fn allocate(&mut self, num_bytes: usize) -> *mut u8 {
+    pub unsafe fn allocate(
+        &mut self,
+        num_bytes: usize,
+    ) -> Result<*mut u8, InternError> {
         let ptr = self.ptr as usize;
         let new_ptr = ptr
             .checked_sub(num_bytes)
@@ -63,17 +197,28 @@ impl LeakyBumpAlloc {
         let new_ptr = new_ptr & !(self.layout.align() - 1);
         let start = self.start as usize;
         if new_ptr < start {
+            #[cfg(feature = "std")]
             eprintln!(
                 "Allocator asked to bump to {} bytes with a capacity of {}",
                 self.end as usize - new_ptr,
                 self.capacity()
             );
-            // Abort instead of panic to avoid poisoning the cache mutex.
-            std::process::abort();
+            abort(self.layout);
+        }
+
+        let new_ptr = new_ptr as *mut u8;
+        #[cfg(all(feature = "mmap", unix))]
+        if new_ptr < self.committed {
+            mmap::commit(self, new_ptr)?;
         }
+        self.ptr = new_ptr;
+        Ok(self.ptr)
+    }
 
-        self.ptr = new_ptr as *mut u8;
-        self.ptr
+    /// The part of the region handed out so far, from the most recent
+    /// allocation up to the end of the region.
+    pub fn allocated_range(&self) -> (*const u8, *const u8) {
+        (self.ptr, self.end)
     }
 
     /// Bytes allocated from this bump region.
@@ -85,4 +230,155 @@ impl LeakyBumpAlloc {
     pub fn capacity(&self) -> usize {
         self.layout.size()
     }
+
+    /// Bytes of this bump region backed by memory, which is less than the
+    /// capacity for regions whose pages are committed on demand.
+    pub fn committed(&self) -> usize {
+        self.end as usize - self.committed as usize
+    }
+
+    /// Where the memory of this bump region comes from.
+    pub fn backing(&self) -> Backing {
+        self.backing.clone()
+    }
+
+    /// Record how much of the region is in use, once everything allocated so
+    /// far has been written. Only regions of a persistent cache file keep
+    /// that record, so that the entries can be found again when the file is
+    /// reopened.
+    #[inline]
+    pub fn publish(&self) {
+        #[cfg(all(feature = "mmap", unix))]
+        if let Backing::File { .. } = self.backing {
+            crate::persist::publish(self.start, self.allocated());
+        }
+    }
+}
+
+#[cfg(all(feature = "mmap", unix))]
+mod mmap {
+    use super::{Backing, LeakyBumpAlloc};
+    use crate::budget::InternError;
+    use alloc::alloc::Layout;
+
+    // Pages are committed in steps of this many bytes, or of a huge page when
+    // huge pages were asked for, so that bumping doesn't make a system call
+    // for every few strings.
+    const COMMIT_STEP: usize = 64 << 10;
+    const HUGE_PAGE: usize = 2 << 20;
+
+    fn page_size() -> usize {
+        // SAFETY: `sysconf` has no preconditions.
+        match unsafe { libc::sysconf(libc::_SC_PAGESIZE) } {
+            n if n > 0 => n as usize,
+            _ => 4096,
+        }
+    }
+
+    // Reserve `capacity` bytes of address space, rounded up to whole pages,
+    // without committing any of it.
+    pub(super) fn reserve(
+        capacity: usize,
+        alignment: usize,
+        huge_pages: bool,
+    ) -> Option<LeakyBumpAlloc> {
+        // Huge pages can only back ranges that are aligned to them, so with
+        // huge pages the range is whole huge pages. Over-reserve by one to be
+        // able to align it, and give back what's left over on either side.
+        let align = if huge_pages { HUGE_PAGE } else { page_size() };
+        let capacity = capacity.max(1).checked_next_multiple_of(align)?;
+        let layout = Layout::from_size_align(capacity, alignment).ok()?;
+        let extra = if huge_pages { HUGE_PAGE } else { 0 };
+        let size = capacity.checked_add(extra)?;
+        // SAFETY: asking for a fresh anonymous mapping has no preconditions.
+        // `PROT_NONE` and `MAP_NORESERVE` keep the range from counting
+        // towards the memory in use until pages are committed.
+        let mapped = unsafe {
+            libc::mmap(
+                core::ptr::null_mut(),
+                size,
+                libc::PROT_NONE,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS | libc::MAP_NORESERVE,
+                -1,
+                0,
+            )
+        };
+        if mapped == libc::MAP_FAILED {
+            return None;
+        }
+        let head = (mapped as usize).next_multiple_of(align) - mapped as usize;
+        // SAFETY: both ranges are part of the mapping, outside of the aligned
+        // range that's kept.
+        let start = unsafe {
+            let start = (mapped as *mut u8).add(head);
+            if head > 0 {
+                libc::munmap(mapped, head);
+            }
+            if extra > head {
+                let tail = start.add(capacity) as *mut libc::c_void;
+                libc::munmap(tail, extra - head);
+            }
+            start as *mut libc::c_void
+        };
+        #[cfg(target_os = "linux")]
+        if huge_pages {
+            // Only a hint: without transparent huge pages this fails and the
+            // range is backed by normal pages.
+            // SAFETY: the range was just mapped.
+            unsafe { libc::madvise(start, capacity, libc::MADV_HUGEPAGE) };
+        }
+        let start = start as *mut u8;
+        // SAFETY: `start + capacity` is one past the end of the mapping.
+        let end = unsafe { start.add(capacity) };
+        Some(LeakyBumpAlloc {
+            layout,
+            start,
+            end,
+            ptr: end,
+            backing: Backing::Mmap { huge_pages },
+            committed: end,
+        })
+    }
+
+    // Commit the pages from `ptr` up to what's already committed.
+    pub(super) fn commit(
+        alloc: &mut LeakyBumpAlloc,
+        ptr: *mut u8,
+    ) -> Result<(), InternError> {
+        let step = match alloc.backing {
+            Backing::Mmap { huge_pages: true } => HUGE_PAGE,
+            _ => COMMIT_STEP,
+        };
+        // Commit whole steps counted from the end, without going past the
+        // start of the mapping.
+        let below = alloc.end as usize - ptr as usize;
+        let below = below.next_multiple_of(step).min(alloc.capacity());
+        let new_committed = alloc.end.wrapping_sub(below);
+        let len = alloc.committed as usize - new_committed as usize;
+        // SAFETY: the range is part of the mapping, and making it writable
+        // doesn't affect anything handed out so far.
+        let res = unsafe {
+            libc::mprotect(
+                new_committed as *mut libc::c_void,
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+            )
+        };
+        if res != 0 {
+            return Err(InternError::OutOfMemory);
+        }
+        alloc.committed = new_committed;
+        Ok(())
+    }
+
+    // Unmap the whole range.
+    //
+    // This is safe as long as nothing handed out by the allocator is used
+    // afterwards.
+    pub(super) unsafe fn release(alloc: &mut LeakyBumpAlloc) {
+        // SAFETY: `alloc.start` was mapped with `alloc.capacity()` bytes.
+        unsafe {
+            libc::munmap(alloc.start as *mut libc::c_void, alloc.capacity());
+        }
+    }
 }
@@ -1,3 +1,7 @@
+#[cfg(feature = "profiling")]
+pub use crate::profile::{StringProfile, coldest, hottest};
+#[cfg(feature = "std")]
+pub use crate::tag::bytes_by_tag;
 use crate::*;
 
 /// DO NOT CALL THIS.
@@ -13,35 +17,307 @@ use crate::*;
 pub unsafe fn _clear_cache() {
     unsafe {
         for m in STRING_CACHE.0.iter() {
-            m.lock().clear();
+            m.clear();
         }
     }
+    crate::budget::reset();
+    crate::front::clear_front_cache();
+    FROZEN.store(false, core::sync::atomic::Ordering::Relaxed);
+}
+
+static FROZEN: core::sync::atomic::AtomicBool =
+    core::sync::atomic::AtomicBool::new(false);
+
+/// Seal the global string cache so that it only serves lookups.
+///
+/// Once frozen, strings that are already in the cache are looked up without
+/// ever taking a lock, while interning a new string fails as configured by
+/// [`CacheConfig::frozen_policy`]. Every [`ArcUstr`] alive at this point
+/// becomes immortal, like a `Ustr`. Freezing can't be undone.
+///
+/// This is meant for programs that intern their whole vocabulary at startup
+/// and then only look strings up.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{InternError, Ustr, ustr};
+///
+/// let hello = ustr("hello");
+/// ustr::freeze();
+/// assert!(ustr::is_frozen());
+///
+/// assert_eq!(Ustr::from_existing("hello"), Some(hello));
+/// assert_eq!(ustr("hello"), hello);
+/// assert_eq!(Ustr::try_from_str("world"), Err(InternError::Frozen));
+/// ```
+pub fn freeze() {
+    for sc in STRING_CACHE.0.iter() {
+        sc.freeze();
+    }
+    FROZEN.store(true, core::sync::atomic::Ordering::Release);
+}
+
+/// Returns true once [`freeze()`] has sealed the global string cache.
+pub fn is_frozen() -> bool {
+    FROZEN.load(core::sync::atomic::Ordering::Acquire)
 }
 
 /// Returns the total amount of memory allocated and in use by the cache in
 /// bytes.
+///
+/// See [`memory_usage()`] for how this splits between the arenas and the
+/// strings stored on their own.
 pub fn total_allocated() -> usize {
-    STRING_CACHE
-        .0
-        .iter()
-        .map(|sc| {
-            
-
-            sc.lock().total_allocated()
-        })
-        .sum()
+    STRING_CACHE.0.iter().map(|sc| sc.total_allocated()).sum()
 }
 
 /// Returns the total amount of memory reserved by the cache in bytes.
+///
+/// See [`memory_usage()`] for how this splits between the arenas and the
+/// strings stored on their own.
 pub fn total_capacity() -> usize {
+    STRING_CACHE.0.iter().map(|sc| sc.total_capacity()).sum()
+}
+
+/// Memory used by the strings in the cache, by where they are stored, as
+/// returned by [`memory_usage()`].
+///
+/// Most strings are bump-allocated from arenas that double in size each time
+/// one fills up. Strings longer than
+/// [`CacheConfig::large_string_threshold`] each get an allocation of their own
+/// instead, as do the strings of live [`ArcUstr`]s, so these are never
+/// reserved ahead of use.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Bytes of the arenas taken by strings.
+    pub arena_allocated: usize,
+    /// Bytes of memory backing the arenas, used or not. With the `mmap`
+    /// feature this only counts the pages committed so far, not the address
+    /// space reserved.
+    pub arena_capacity: usize,
+    /// Number of strings stored in an allocation of their own because of
+    /// their length.
+    pub large_strings: usize,
+    /// Bytes in the allocations of those strings.
+    pub large_bytes: usize,
+    /// Bytes in the allocations of the strings of live `ArcUstr`s.
+    pub counted_bytes: usize,
+}
+
+impl MemoryUsage {
+    /// Bytes in use by strings, as returned by [`total_allocated()`].
+    pub fn allocated(&self) -> usize {
+        self.arena_allocated + self.large_bytes + self.counted_bytes
+    }
+
+    /// Bytes reserved for strings, as returned by [`total_capacity()`].
+    pub fn capacity(&self) -> usize {
+        self.arena_capacity + self.large_bytes + self.counted_bytes
+    }
+}
+
+impl core::iter::Sum for MemoryUsage {
+    fn sum<I: Iterator<Item = MemoryUsage>>(iter: I) -> MemoryUsage {
+        iter.fold(MemoryUsage::default(), |total, m| MemoryUsage {
+            arena_allocated: total.arena_allocated + m.arena_allocated,
+            arena_capacity: total.arena_capacity + m.arena_capacity,
+            large_strings: total.large_strings + m.large_strings,
+            large_bytes: total.large_bytes + m.large_bytes,
+            counted_bytes: total.counted_bytes + m.counted_bytes,
+        })
+    }
+}
+
+/// Returns the memory used by the cache's strings, split by where they are
+/// stored.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::ustr;
+/// # unsafe { ustr::_clear_cache() };
+///
+/// let big = "x".repeat(1 << 20);
+/// ustr(&big);
+/// let usage = ustr::memory_usage();
+/// assert_eq!(usage.large_strings, 1);
+/// assert!(usage.large_bytes > big.len());
+/// assert_eq!(usage.capacity(), ustr::total_capacity());
+/// ```
+pub fn memory_usage() -> MemoryUsage {
+    STRING_CACHE.0.iter().map(|sc| sc.memory_usage()).sum()
+}
+
+/// Returns the longest time a single operation has spent growing the table of
+/// any bin.
+///
+/// Tables grow incrementally: each insert moves a bounded number of entries
+/// from the old table to the new one, so this stays small no matter how many
+/// strings the cache holds.
+pub fn max_growth_pause() -> core::time::Duration {
     STRING_CACHE
         .0
         .iter()
-        .map(|sc| {
-            
-            sc.lock().total_capacity()
-        })
-        .sum()
+        .map(StringCache::max_pause)
+        .max()
+        .unwrap_or_default()
+}
+
+/// The shape of one bin (shard) of the global cache, as reported by
+/// [`stats()`].
+///
+/// Probe distances count the slots a lookup steps over past the one the
+/// string's hash points to, so 0 means a string is found in its first slot.
+/// They stay close to 0 unless the hash spreads strings badly, or the table is
+/// full of tombstones left by dropped [`ArcUstr`]s.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BinStats {
+    /// Number of strings in the bin.
+    pub entries: usize,
+    /// Number of slots in the bin's table.
+    pub table_size: usize,
+    /// Fraction of the table's slots holding a string. The table doubles
+    /// before this goes over 0.5.
+    pub load_factor: f64,
+    /// Number of slots holding a tombstone.
+    pub tombstones: usize,
+    /// Whether strings are still being moved over from the table this one
+    /// replaced.
+    pub migrating: bool,
+    /// Longest probe distance of any string.
+    pub max_probe_distance: usize,
+    /// Mean probe distance of the strings.
+    pub mean_probe_distance: f64,
+    /// Number of strings by length: bucket `i` counts strings of `i`
+    /// significant bits of length, that is lengths from `2^(i-1)` up to
+    /// `2^i - 1`, with bucket 0 for the empty string. The last bucket also
+    /// counts every longer string.
+    pub length_histogram: [usize; BinStats::LENGTH_BUCKETS],
+    /// Number of arenas strings have been bump-allocated from, including the
+    /// current one.
+    pub arenas: usize,
+    /// Bytes of the arenas lost to aligning each string's entry.
+    pub padding_bytes: usize,
+    /// Bytes left unused at the end of arenas that were replaced by a bigger
+    /// one because the next string didn't fit.
+    pub tail_bytes: usize,
+    /// Number of times the bin's lock was taken to add or remove strings.
+    pub lock_acquisitions: u64,
+    /// Number of those times the lock was held by another thread.
+    pub lock_contentions: u64,
+    /// Longest time a single operation has spent growing the bin's table.
+    pub max_growth_pause: core::time::Duration,
+}
+
+impl BinStats {
+    /// Number of buckets in [`BinStats::length_histogram`].
+    pub const LENGTH_BUCKETS: usize = 16;
+}
+
+/// A snapshot of the global cache, as returned by [`stats()`].
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheStats {
+    /// The state of each bin, in order.
+    pub bins: Vec<BinStats>,
+    /// Hits and misses of the thread-local front caches.
+    pub front_cache: FrontCacheStats,
+}
+
+impl CacheStats {
+    /// The bins added up into one: counts and bytes are summed, maxima are
+    /// taken over all bins, and ratios are weighted by the size of each bin.
+    pub fn total(&self) -> BinStats {
+        let mut total = BinStats::default();
+        let mut distance = 0.0;
+        for bin in &self.bins {
+            total.entries += bin.entries;
+            total.table_size += bin.table_size;
+            total.load_factor += bin.load_factor * bin.table_size as f64;
+            total.tombstones += bin.tombstones;
+            total.migrating |= bin.migrating;
+            total.max_probe_distance =
+                total.max_probe_distance.max(bin.max_probe_distance);
+            distance += bin.mean_probe_distance * bin.entries as f64;
+            for (t, n) in
+                total.length_histogram.iter_mut().zip(bin.length_histogram)
+            {
+                *t += n;
+            }
+            total.arenas += bin.arenas;
+            total.padding_bytes += bin.padding_bytes;
+            total.tail_bytes += bin.tail_bytes;
+            total.lock_acquisitions += bin.lock_acquisitions;
+            total.lock_contentions += bin.lock_contentions;
+            total.max_growth_pause =
+                total.max_growth_pause.max(bin.max_growth_pause);
+        }
+        if total.table_size > 0 {
+            total.load_factor /= total.table_size as f64;
+        }
+        if total.entries > 0 {
+            total.mean_probe_distance = distance / total.entries as f64;
+        }
+        total
+    }
+}
+
+/// Returns a snapshot of the shape of every bin of the global cache, to tune
+/// [`CacheConfig::bins`] or notice when a bin degrades.
+///
+/// Each bin's lock is held while its table is walked, which takes time in
+/// proportion to the size of the table, so this is meant to be polled for
+/// monitoring rather than called on a hot path. Bins are walked one after the
+/// other, so the snapshot isn't atomic across bins if other threads are
+/// interning. With the `dylib` feature, this describes the bins of this copy
+/// of ustr, which stay empty unless it owns the shared cache.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::ustr;
+/// # unsafe { ustr::_clear_cache() };
+///
+/// ustr("hello");
+/// ustr("world!");
+/// let stats = ustr::cache::stats();
+/// let total = stats.total();
+/// assert_eq!(total.entries, 2);
+/// // Both strings are 5 to 7 bytes long, which take 3 bits.
+/// assert_eq!(total.length_histogram[3], 2);
+/// assert!(total.load_factor <= 0.5);
+/// ```
+pub fn stats() -> CacheStats {
+    CacheStats {
+        bins: STRING_CACHE.0.iter().map(StringCache::stats).collect(),
+        front_cache: front_cache_stats(),
+    }
+}
+
+/// Update the gauges of every bin of the global cache now.
+///
+/// With the `metrics` feature, each bin of the global cache publishes its
+/// size through the [`metrics`](https://docs.rs/metrics) facade, labelled
+/// with the bin's index as `bin`:
+///
+/// - `ustr_entries`: the number of strings (gauge).
+/// - `ustr_allocated_bytes`: bytes in use by strings, as in
+///   [`total_allocated()`] (gauge).
+/// - `ustr_capacity_bytes`: bytes reserved for strings, as in
+///   [`total_capacity()`] (gauge).
+/// - `ustr_table_grows`: times the bin's table has grown (counter).
+/// - `ustr_arena_rotations`: times the bin has moved on to a new, bigger
+///   arena (counter).
+///
+/// A bin updates its gauges when it adds a string, if it has gained or lost
+/// 1024 strings since the last update, grown its table or taken new memory
+/// for strings. Call this before reading the gauges to make them exact, for
+/// example from the exporter's scrape handler.
+#[cfg(feature = "metrics")]
+pub fn publish_metrics() {
+    for sc in STRING_CACHE.0.iter() {
+        sc.publish_metrics();
+    }
 }
 
 /// Utility function to get a reference to the main cache object for use with
@@ -76,14 +352,11 @@ pub fn cache() -> &'static Bins {
 /// assert_eq!(ustr::num_entries(), 2);
 /// ```
 pub fn num_entries() -> usize {
-    STRING_CACHE
-        .0
-        .iter()
-        .map(|sc| {
-            
-            sc.lock().num_entries()
-        })
-        .sum()
+    #[cfg(all(feature = "dylib", target_os = "linux"))]
+    if let Some(owner) = crate::dylib::owner() {
+        return owner.num_entries();
+    }
+    STRING_CACHE.0.iter().map(|sc| sc.num_entries()).sum()
 }
 
 #[doc(hidden)]
@@ -91,38 +364,39 @@ pub fn num_entries_per_bin() -> Vec<usize> {
     STRING_CACHE
         .0
         .iter()
-        .map(|sc| {
-            
-            sc.lock().num_entries()
-        })
+        .map(|sc| sc.num_entries())
         .collect::<Vec<_>>()
 }
 
 /// Return an iterator over the entire string cache.
 ///
-/// If another thread is adding strings concurrently to this call then they
-/// might not show up in the view of the cache presented by this iterator.
+/// Each bin is locked in turn only long enough to note where its strings are
+/// stored; the strings themselves are read as the iterator advances. Strings
+/// added to the cache after this call aren't yielded.
 ///
-/// # Safety
+/// # Examples
+///
+/// ```
+/// use ustr::ustr;
+/// # unsafe { ustr::_clear_cache() };
 ///
-/// This returns an iterator to the state of the cache at the time when
-/// `string_cache_iter()` was called. It is of course possible that another
-/// thread will add more strings to the cache after this, but since we never
-/// destroy the strings, they remain valid, meaning it's safe to iterate over
-/// them, the list just might not be completely up to date.
+/// let hello = ustr("hello");
+/// let mut iter = ustr::string_cache_iter();
+/// assert_eq!(iter.len(), 1);
+/// let u = iter.next().unwrap();
+/// assert_eq!(u, hello);
+/// assert_eq!(u.precomputed_hash(), hello.precomputed_hash());
+/// ```
 pub fn string_cache_iter() -> StringCacheIterator {
-    let mut all_strings = Vec::new();
-
+    let mut ranges = Vec::new();
+    let mut remaining = 0;
     for m in STRING_CACHE.0.iter() {
-        let sc = m.lock();
-        // Collect all strings from this cache shard
-        all_strings.extend_from_slice(&sc.all_strings);
+        remaining += m.snapshot(&mut ranges);
     }
+    // The iterator walks the ranges from the back.
+    ranges.reverse();
 
-    StringCacheIterator {
-        strings: all_strings,
-        current: 0,
-    }
+    StringCacheIterator { ranges, remaining }
 }
 
 /// The type used for the global string cache.
@@ -130,4 +404,56 @@ pub fn string_cache_iter() -> StringCacheIterator {
 /// This is exposed to allow e.g. serialization of the data returned by the
 /// [`cache()`] function.
 #[repr(transparent)]
-pub struct Bins(pub(crate) [Mutex<StringCache>; NUM_BINS]);
+pub struct Bins(pub(crate) Box<[StringCache]>);
+
+impl Bins {
+    pub(crate) fn new(config: &CacheConfig) -> Bins {
+        Bins(
+            (0..config.bins)
+                // Only used to label the bin's metrics.
+                .map(|#[allow(unused_variables)] bin| {
+                    let sc = StringCache::new(
+                        config.bin_table_capacity(),
+                        config.bin_arena_bytes(),
+                        config.growth_factor,
+                    )
+                    .budgeted()
+                    .large_string_threshold(config.large_string_threshold)
+                    .frozen_policy(config.frozen_policy);
+                    #[cfg(feature = "std")]
+                    let sc = sc.record_tags();
+                    #[cfg(feature = "metrics")]
+                    let sc = sc.metrics_bin(bin);
+                    #[cfg(all(feature = "mmap", unix))]
+                    let sc = match config.arena_reserve_bytes {
+                        0 => sc,
+                        reserve => sc.mmap_arena(reserve, config.huge_pages),
+                    };
+                    sc
+                })
+                .collect(),
+        )
+    }
+
+    // The bins of the global cache: those loaded from a persistent cache file
+    // if one was opened, new ones otherwise.
+    pub(crate) fn init() -> Bins {
+        #[cfg(all(feature = "mmap", unix))]
+        if let Some(bins) = crate::persist::take_opened() {
+            return bins;
+        }
+        Bins::new(&config::take_for_init())
+    }
+
+    #[inline]
+    pub(crate) fn whichbin(&self, hash: u64) -> usize {
+        bin_for(hash, self.0.len())
+    }
+}
+
+// Use the top bits of the hash to choose one of `bins` bins, a power of two.
+#[inline]
+pub(crate) fn bin_for(hash: u64, bins: usize) -> usize {
+    let bin_shift = bins.trailing_zeros();
+    hash.checked_shr(64 - bin_shift).unwrap_or(0) as usize
+}
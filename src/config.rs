@@ -0,0 +1,239 @@
+use crate::stringcache::{
+    INITIAL_ALLOC, INITIAL_CAPACITY, LARGE_STRING_THRESHOLD, NUM_BINS,
+};
+use crate::sync::Mutex;
+use core::{
+    fmt,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// Configuration of the global string cache.
+///
+/// Pass this to [`configure()`] before the first `Ustr` is created to change
+/// how the cache is laid out. Any field not given explicitly can be taken from
+/// [`CacheConfig::default()`], which matches the built-in settings.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::CacheConfig;
+///
+/// let config = CacheConfig {
+///     initial_table_capacity: 1 << 12,
+///     initial_arena_bytes: 64 << 10,
+///     ..CacheConfig::default()
+/// };
+/// ustr::configure(config).unwrap();
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CacheConfig {
+    /// Number of bins (shards) the cache is split into, each with their own
+    /// lock. Must be a non-zero power of two, and 1 with the `single-thread`
+    /// feature.
+    pub bins: usize,
+    /// Initial number of slots in the hash table, divided evenly among the
+    /// bins. Each bin gets at least two slots, rounded up to a power of two.
+    pub initial_table_capacity: usize,
+    /// Initial size in bytes of the string storage, divided evenly among the
+    /// bins.
+    pub initial_arena_bytes: usize,
+    /// Factor by which the string storage of a bin grows each time it fills
+    /// up. Must be at least 1.
+    pub growth_factor: usize,
+    /// Length in bytes above which a string is stored in an allocation of its
+    /// own rather than in the string storage of its bin, so that a few huge
+    /// strings don't make the storage grow. See
+    /// [`memory_usage()`](crate::memory_usage).
+    pub large_string_threshold: usize,
+    /// Bytes of address space reserved for the string storage of each bin,
+    /// or 0 to allocate it from the heap. Pages are only committed as strings
+    /// are added, so [`total_capacity()`](crate::total_capacity) stays close
+    /// to what's in use, and a bin's strings stay in one contiguous range
+    /// until it fills up. This takes the place of
+    /// [`initial_arena_bytes`](CacheConfig::initial_arena_bytes). Ignored on
+    /// platforms other than Unix.
+    ///
+    /// Defaults to 0. The reservation is made for every bin, so with the
+    /// default 64 bins even a modest size adds up to a lot of address space,
+    /// which can fail under `ulimit -v` or strict overcommit.
+    #[cfg(feature = "mmap")]
+    pub arena_reserve_bytes: usize,
+    /// Ask for the reserved string storage to be backed by transparent huge
+    /// pages, which cuts down on TLB misses when reading strings from a large
+    /// cache. The reserved range is then rounded up to whole 2MB pages and
+    /// aligned to them, and committed 2MB at a time. Whether huge pages are
+    /// used in the end is up to the kernel's transparent huge page settings.
+    /// Only has an effect on Linux.
+    #[cfg(feature = "mmap")]
+    pub huge_pages: bool,
+    /// What happens when a new string is interned after the cache has been
+    /// sealed with [`freeze()`](crate::freeze).
+    pub frozen_policy: FrozenPolicy,
+}
+
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig {
+            bins: NUM_BINS,
+            initial_table_capacity: INITIAL_CAPACITY,
+            initial_arena_bytes: INITIAL_ALLOC,
+            growth_factor: 2,
+            large_string_threshold: LARGE_STRING_THRESHOLD,
+            #[cfg(feature = "mmap")]
+            arena_reserve_bytes: 0,
+            #[cfg(feature = "mmap")]
+            huge_pages: false,
+            frozen_policy: FrozenPolicy::Error,
+        }
+    }
+}
+
+/// What happens when a new string is interned into a frozen cache.
+///
+/// Strings that are already in the cache can always be interned and looked
+/// up, this only applies to strings that would have to be added.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FrozenPolicy {
+    /// Fallible functions like [`Ustr::try_from_str`](crate::Ustr::try_from_str)
+    /// return [`InternError::Frozen`](crate::InternError::Frozen), and
+    /// [`Ustr::from`](crate::Ustr::from) panics.
+    #[default]
+    Error,
+    /// Panic, even in fallible functions. Useful to catch code that was
+    /// expected to only see the vocabulary interned before freezing.
+    Panic,
+}
+
+impl CacheConfig {
+    /// Number of hash table slots each bin starts with.
+    pub(crate) fn bin_table_capacity(&self) -> usize {
+        (self.initial_table_capacity / self.bins)
+            .max(2)
+            .next_power_of_two()
+    }
+
+    /// Number of bytes of string storage each bin starts with.
+    pub(crate) fn bin_arena_bytes(&self) -> usize {
+        (self.initial_arena_bytes / self.bins).max(1)
+    }
+
+    fn validate(&self) -> Result<(), ConfigError> {
+        if !self.bins.is_power_of_two()
+            || cfg!(feature = "single-thread") && self.bins != 1
+        {
+            return Err(ConfigError::InvalidBins(self.bins));
+        }
+        if self.growth_factor == 0 {
+            return Err(ConfigError::InvalidGrowthFactor(self.growth_factor));
+        }
+        Ok(())
+    }
+}
+
+/// Error returned by [`configure()`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    /// The global cache was already created, so its layout can no longer be
+    /// changed.
+    AlreadyInitialized,
+    /// The number of bins was zero or not a power of two, or not 1 with the
+    /// `single-thread` feature.
+    InvalidBins(usize),
+    /// The growth factor was zero.
+    InvalidGrowthFactor(usize),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::AlreadyInitialized => {
+                write!(f, "the string cache has already been initialized")
+            }
+            ConfigError::InvalidBins(bins)
+                if cfg!(feature = "single-thread") =>
+            {
+                write!(
+                    f,
+                    "the `single-thread` feature requires a single bin, got \
+                     {bins}"
+                )
+            }
+            ConfigError::InvalidBins(bins) => {
+                write!(f, "number of bins must be a power of two, got {bins}")
+            }
+            ConfigError::InvalidGrowthFactor(factor) => {
+                write!(f, "growth factor must be at least 1, got {factor}")
+            }
+        }
+    }
+}
+
+impl core::error::Error for ConfigError {}
+
+// The configuration the global cache will be created with. Guarded by a lock
+// so that `configure()` can't race with the cache being initialized.
+static CONFIG: Mutex<Option<CacheConfig>> = Mutex::new(None);
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+
+/// Configure the global string cache.
+///
+/// This must be called before the first `Ustr` is created (or any other
+/// function touching the cache is called). Once the cache has been
+/// initialized its layout is fixed and this returns
+/// [`ConfigError::AlreadyInitialized`].
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{CacheConfig, ConfigError, ustr};
+///
+/// // Small tools don't need 4MB of string storage up front.
+/// ustr::configure(CacheConfig {
+///     initial_arena_bytes: 64 << 10,
+///     ..CacheConfig::default()
+/// })
+/// .unwrap();
+///
+/// let _ = ustr("hello");
+/// assert_eq!(
+///     ustr::configure(CacheConfig::default()),
+///     Err(ConfigError::AlreadyInitialized)
+/// );
+/// ```
+pub fn configure(config: CacheConfig) -> Result<(), ConfigError> {
+    config.validate()?;
+    let mut current = CONFIG.lock();
+    if INITIALIZED.load(Ordering::Acquire) {
+        return Err(ConfigError::AlreadyInitialized);
+    }
+    *current = Some(config);
+    Ok(())
+}
+
+/// Returns the configuration the global cache is created with and marks the
+/// cache as initialized.
+pub(crate) fn take_for_init() -> CacheConfig {
+    let mut current = CONFIG.lock();
+    INITIALIZED.store(true, Ordering::Release);
+    current.take().unwrap_or_default()
+}
+
+// Run `f` with the configuration the global cache is to be created with, and
+// mark the cache as initialized if it succeeds. Returns `None` if the cache
+// was already initialized.
+#[cfg(all(feature = "mmap", unix))]
+pub(crate) fn init_with<T, E>(
+    f: impl FnOnce(&CacheConfig) -> Result<T, E>,
+) -> Option<Result<T, E>> {
+    let mut current = CONFIG.lock();
+    if INITIALIZED.load(Ordering::Acquire) {
+        return None;
+    }
+    let config = current.take().unwrap_or_default();
+    let res = f(&config);
+    match res {
+        Ok(_) => INITIALIZED.store(true, Ordering::Release),
+        Err(_) => *current = Some(config),
+    }
+    Some(res)
+}
@@ -0,0 +1,597 @@
+use crate::{
+    InternError, STRING_CACHE,
+    stringcache::{RcEntry, StringCacheEntry},
+};
+use std::{
+    ffi::{CStr, CString, c_int, c_void},
+    fmt,
+    panic::{self, AssertUnwindSafe},
+    slice, str,
+};
+
+// Every cdylib that statically links ustr carries its own copy of the crate,
+// and so its own `STRING_CACHE`. To keep `Ustr`s comparable across them, each
+// copy exports `ustr_global_cache()`, which returns the `Registration` of that
+// copy's cache: a plain C table of functions working on it. On first use,
+// each copy walks the objects loaded in the process, in load order, and picks
+// the first one exporting the function as the owner of the global cache.
+// Every copy makes the same choice, and the owner finds itself.
+//
+// Copies that don't own the cache send every string through the owner's
+// functions, so the owner's code, locks and allocators do all the work, and
+// the copies only need to agree on the layout of an entry and on the hash
+// function. They still read entries directly, as any `Ustr` does.
+//
+// The symbol keeps its name across releases. The first two fields of a
+// `Registration` are fixed forever, so a copy always finds out whether the
+// owner speaks its version, and refuses to run with one that doesn't rather
+// than silently keeping a cache of its own.
+
+const SYMBOL: &CStr = c"ustr_global_cache";
+const VERSION: u32 = 1;
+// Hashed to check that every copy uses the same hash function.
+const HASH_PROBE: &[u8] = b"ustr global cache";
+
+// Why an interning call failed, passed back across the C boundary.
+#[repr(C)]
+#[derive(Default)]
+struct RawError {
+    kind: u32,
+    len: usize,
+    max: usize,
+}
+
+const STRING_TOO_LONG: u32 = 1;
+const TOO_MANY_ENTRIES: u32 = 2;
+const TOO_MANY_BYTES: u32 = 3;
+const OUT_OF_MEMORY: u32 = 4;
+const FROZEN: u32 = 5;
+
+impl RawError {
+    fn new(e: InternError) -> RawError {
+        match e {
+            InternError::StringTooLong { len, max } => RawError {
+                kind: STRING_TOO_LONG,
+                len,
+                max,
+            },
+            InternError::TooManyEntries { max } => RawError {
+                kind: TOO_MANY_ENTRIES,
+                len: 0,
+                max,
+            },
+            InternError::TooManyBytes { max } => RawError {
+                kind: TOO_MANY_BYTES,
+                len: 0,
+                max,
+            },
+            InternError::OutOfMemory => RawError {
+                kind: OUT_OF_MEMORY,
+                ..RawError::default()
+            },
+            InternError::Frozen => RawError {
+                kind: FROZEN,
+                ..RawError::default()
+            },
+        }
+    }
+
+    fn to_error(&self) -> InternError {
+        match self.kind {
+            STRING_TOO_LONG => InternError::StringTooLong {
+                len: self.len,
+                max: self.max,
+            },
+            TOO_MANY_ENTRIES => InternError::TooManyEntries { max: self.max },
+            TOO_MANY_BYTES => InternError::TooManyBytes { max: self.max },
+            FROZEN => InternError::Frozen,
+            _ => InternError::OutOfMemory,
+        }
+    }
+}
+
+/// What one copy of ustr publishes about its global cache.
+#[repr(C)]
+pub(crate) struct Registration {
+    // These two fields stay in place in every version.
+    version: u32,
+    size: u32,
+    pointer_width: u32,
+    entry_size: u32,
+    rc_entry_size: u32,
+    entry_align: u32,
+    // `hash::hash(HASH_PROBE)`
+    hash_check: u64,
+    // Each of these gives back the chars of the string's entry, or null.
+    intern:
+        unsafe extern "C" fn(*const u8, usize, u64, *mut RawError) -> *const u8,
+    get_existing: unsafe extern "C" fn(*const u8, usize, u64) -> *const u8,
+    // These also say whether a reference was taken on the entry.
+    intern_counted: unsafe extern "C" fn(
+        *const u8,
+        usize,
+        u64,
+        *mut bool,
+        *mut RawError,
+    ) -> *const u8,
+    get_existing_counted:
+        unsafe extern "C" fn(*const u8, usize, u64, *mut bool) -> *const u8,
+    // Drops what may be the last reference to a counted entry.
+    release_counted: unsafe extern "C" fn(*const u8),
+    num_entries: extern "C" fn() -> usize,
+}
+
+// The functions only touch the cache through its locks and atomics.
+unsafe impl Sync for Registration {}
+
+impl Registration {
+    fn current() -> Registration {
+        Registration {
+            version: VERSION,
+            size: std::mem::size_of::<Registration>() as u32,
+            pointer_width: usize::BITS,
+            entry_size: std::mem::size_of::<StringCacheEntry>() as u32,
+            rc_entry_size: std::mem::size_of::<RcEntry>() as u32,
+            entry_align: std::mem::align_of::<StringCacheEntry>() as u32,
+            hash_check: crate::hash::hash(HASH_PROBE),
+            intern,
+            get_existing,
+            intern_counted,
+            get_existing_counted,
+            release_counted,
+            num_entries,
+        }
+    }
+
+    // Check that the owner's registration can be used by this copy.
+    //
+    // This only reads `version` and `size` until they have been checked.
+    unsafe fn check(reg: *const Registration) -> Result<(), Incompatible> {
+        let (version, size) = unsafe { ((*reg).version, (*reg).size) };
+        if version != VERSION
+            || size as usize != std::mem::size_of::<Registration>()
+        {
+            return Err(Incompatible::Version(version));
+        }
+        let reg = unsafe { &*reg };
+        let current = Registration::current();
+        if reg.pointer_width != current.pointer_width
+            || reg.entry_size != current.entry_size
+            || reg.rc_entry_size != current.rc_entry_size
+            || reg.entry_align != current.entry_align
+        {
+            Err(Incompatible::Layout)
+        } else if reg.hash_check != current.hash_check {
+            Err(Incompatible::Hash)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn intern(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Result<*const u8, InternError> {
+        let mut error = RawError::default();
+        let ptr = unsafe {
+            (self.intern)(string.as_ptr(), string.len(), hash, &mut error)
+        };
+        if ptr.is_null() {
+            Err(error.to_error())
+        } else {
+            Ok(ptr)
+        }
+    }
+
+    pub(crate) fn get_existing(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Option<*const u8> {
+        let ptr =
+            unsafe { (self.get_existing)(string.as_ptr(), string.len(), hash) };
+        (!ptr.is_null()).then_some(ptr)
+    }
+
+    pub(crate) fn intern_counted(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Result<(*const u8, bool), InternError> {
+        let mut counted = false;
+        let mut error = RawError::default();
+        let ptr = unsafe {
+            (self.intern_counted)(
+                string.as_ptr(),
+                string.len(),
+                hash,
+                &mut counted,
+                &mut error,
+            )
+        };
+        if ptr.is_null() {
+            Err(error.to_error())
+        } else {
+            Ok((ptr, counted))
+        }
+    }
+
+    pub(crate) fn get_existing_counted(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Option<(*const u8, bool)> {
+        let mut counted = false;
+        let ptr = unsafe {
+            (self.get_existing_counted)(
+                string.as_ptr(),
+                string.len(),
+                hash,
+                &mut counted,
+            )
+        };
+        (!ptr.is_null()).then_some((ptr, counted))
+    }
+
+    // This is safe as long as `chars` are those of a counted entry and the
+    // caller holds a reference to it, which it gives up.
+    pub(crate) unsafe fn release_counted(&self, chars: *const u8) {
+        unsafe { (self.release_counted)(chars) }
+    }
+
+    pub(crate) fn num_entries(&self) -> usize {
+        (self.num_entries)()
+    }
+}
+
+// The reason a copy of ustr refuses to use the global cache of another.
+#[derive(Debug, PartialEq)]
+enum Incompatible {
+    Version(u32),
+    Layout,
+    Hash,
+}
+
+impl fmt::Display for Incompatible {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Incompatible::Version(version) => write!(
+                f,
+                "its registration version is {version}, expected {VERSION}"
+            ),
+            Incompatible::Layout => {
+                f.write_str("its entries have another layout")
+            }
+            Incompatible::Hash => f.write_str("it uses another hash function"),
+        }
+    }
+}
+
+lazy_static::lazy_static! {
+    static ref REGISTRATION: Registration = Registration::current();
+    // The registration of the copy owning the global cache, if it's not this
+    // one.
+    static ref OWNER: Option<&'static Registration> = find_owner();
+}
+
+/// Returns the registration of the global string cache of this copy of ustr.
+///
+/// This is how copies of ustr linked into different shared objects of one
+/// process find each other, see the `dylib` feature. It's not meant to be
+/// called by anything else.
+#[doc(hidden)]
+#[unsafe(no_mangle)]
+pub extern "C" fn ustr_global_cache() -> *const c_void {
+    &*REGISTRATION as *const Registration as *const c_void
+}
+
+// The registration of the copy of ustr that owns the global cache, or `None`
+// if this copy owns it.
+#[inline]
+pub(crate) fn owner() -> Option<&'static Registration> {
+    *OWNER
+}
+
+fn find_owner() -> Option<&'static Registration> {
+    let reg = first_registration()? as *const Registration;
+    if std::ptr::eq(reg, &*REGISTRATION) {
+        return None;
+    }
+    // SAFETY: the symbol is only exported by ustr, and every version starts
+    // its registration with the version and size.
+    if let Err(e) = unsafe { Registration::check(reg) } {
+        panic!(
+            "ustr: the global string cache is owned by an incompatible copy \
+             of ustr: {e}"
+        );
+    }
+    Some(unsafe { &*reg })
+}
+
+// Get the registration of the first loaded object exporting `SYMBOL`.
+fn first_registration() -> Option<*const c_void> {
+    unsafe extern "C" fn collect(
+        info: *mut libc::dl_phdr_info,
+        _size: usize,
+        data: *mut c_void,
+    ) -> c_int {
+        let names = unsafe { &mut *(data as *mut Vec<Option<CString>>) };
+        let name = unsafe { (*info).dlpi_name };
+        // The main program has an empty name.
+        names.push(if name.is_null() || unsafe { *name } == 0 {
+            None
+        } else {
+            Some(unsafe { CStr::from_ptr(name) }.to_owned())
+        });
+        0
+    }
+
+    // Objects are listed in load order. They're only looked at once the list
+    // is complete, so as not to call `dlopen()` with the loader's list locked.
+    let mut names: Vec<Option<CString>> = Vec::new();
+    unsafe {
+        libc::dl_iterate_phdr(
+            Some(collect),
+            &mut names as *mut Vec<Option<CString>> as *mut c_void,
+        );
+    }
+
+    names.iter().find_map(|name| {
+        let name = name.as_ref().map_or(std::ptr::null(), |n| n.as_ptr());
+        // This only gives a handle to objects that are loaded already, and
+        // finds symbols in those loaded with `RTLD_LOCAL` too.
+        let handle =
+            unsafe { libc::dlopen(name, libc::RTLD_LAZY | libc::RTLD_NOLOAD) };
+        if handle.is_null() {
+            return None;
+        }
+        let sym = unsafe { libc::dlsym(handle, SYMBOL.as_ptr()) };
+        unsafe { libc::dlclose(handle) };
+        if sym.is_null() {
+            return None;
+        }
+        let register: extern "C" fn() -> *const c_void =
+            unsafe { std::mem::transmute(sym) };
+        Some(register())
+    })
+}
+
+// The owner's side of a registration. These work on the owner's own cache and
+// must not unwind into the caller, so a panic, such as the one asked for by
+// `FrozenPolicy::Panic`, is reported as the cache being frozen.
+
+unsafe fn as_str<'a>(ptr: *const u8, len: usize) -> &'a str {
+    unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) }
+}
+
+unsafe extern "C" fn intern(
+    ptr: *const u8,
+    len: usize,
+    hash: u64,
+    error: *mut RawError,
+) -> *const u8 {
+    let string = unsafe { as_str(ptr, len) };
+    let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+    let result =
+        panic::catch_unwind(AssertUnwindSafe(|| sc.try_insert(string, hash)))
+            .unwrap_or(Err(InternError::Frozen));
+    result.unwrap_or_else(|e| {
+        unsafe { *error = RawError::new(e) };
+        std::ptr::null()
+    })
+}
+
+unsafe extern "C" fn get_existing(
+    ptr: *const u8,
+    len: usize,
+    hash: u64,
+) -> *const u8 {
+    let string = unsafe { as_str(ptr, len) };
+    let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+    sc.get_existing(string, hash).unwrap_or(std::ptr::null())
+}
+
+unsafe extern "C" fn intern_counted(
+    ptr: *const u8,
+    len: usize,
+    hash: u64,
+    counted: *mut bool,
+    error: *mut RawError,
+) -> *const u8 {
+    let string = unsafe { as_str(ptr, len) };
+    let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+    let result = panic::catch_unwind(AssertUnwindSafe(|| {
+        sc.try_insert_counted(string, hash)
+    }))
+    .unwrap_or(Err(InternError::Frozen));
+    match result {
+        Ok((ptr, c)) => {
+            unsafe { *counted = c };
+            ptr
+        }
+        Err(e) => {
+            unsafe { *error = RawError::new(e) };
+            std::ptr::null()
+        }
+    }
+}
+
+unsafe extern "C" fn get_existing_counted(
+    ptr: *const u8,
+    len: usize,
+    hash: u64,
+    counted: *mut bool,
+) -> *const u8 {
+    let string = unsafe { as_str(ptr, len) };
+    let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+    match sc.get_existing_counted(string, hash) {
+        Some((ptr, c)) => {
+            unsafe { *counted = c };
+            ptr
+        }
+        None => std::ptr::null(),
+    }
+}
+
+unsafe extern "C" fn release_counted(chars: *const u8) {
+    let entry = unsafe { chars.cast::<StringCacheEntry>().sub(1) };
+    unsafe { crate::arcustr::release_last(entry as *mut StringCacheEntry) }
+}
+
+extern "C" fn num_entries() -> usize {
+    STRING_CACHE.0.iter().map(|sc| sc.num_entries()).sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{os::raw::c_char, path::Path, process::Command};
+
+    // Set in the process spawned by `test_plugins` to the directory holding
+    // the plugins, which it loads so as not to change the cache of the other
+    // tests.
+    const CHILD: &str = "USTR_DYLIB_TEST_CHILD";
+
+    struct Plugin {
+        intern: unsafe extern "C" fn(*const u8, usize) -> *const c_char,
+        existing: unsafe extern "C" fn(*const u8, usize) -> *const c_char,
+        arc: unsafe extern "C" fn(*const u8, usize) -> *mut c_void,
+        drop_arc: unsafe extern "C" fn(*mut c_void),
+        num_entries: extern "C" fn() -> usize,
+    }
+
+    impl Plugin {
+        fn load(path: &Path) -> Plugin {
+            let path = CString::new(path.to_str().unwrap()).unwrap();
+            let handle = unsafe {
+                libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL)
+            };
+            assert!(!handle.is_null());
+            // This is safe as long as `F` is the type of the function.
+            unsafe fn sym<F: Copy>(handle: *mut c_void, name: &CStr) -> F {
+                let sym = unsafe { libc::dlsym(handle, name.as_ptr()) };
+                assert!(!sym.is_null(), "{name:?} not found");
+                unsafe { std::mem::transmute_copy(&sym) }
+            }
+            unsafe {
+                Plugin {
+                    intern: sym(handle, c"plugin_intern"),
+                    existing: sym(handle, c"plugin_existing"),
+                    arc: sym(handle, c"plugin_arc"),
+                    drop_arc: sym(handle, c"plugin_drop_arc"),
+                    num_entries: sym(handle, c"plugin_num_entries"),
+                }
+            }
+        }
+
+        fn intern(&self, s: &str) -> *const c_char {
+            unsafe { (self.intern)(s.as_ptr(), s.len()) }
+        }
+
+        fn existing(&self, s: &str) -> *const c_char {
+            unsafe { (self.existing)(s.as_ptr(), s.len()) }
+        }
+    }
+
+    #[test]
+    fn test_plugins() {
+        if let Ok(dir) = std::env::var(CHILD) {
+            let dir = Path::new(&dir);
+            let a = Plugin::load(&dir.join("plugin_a.so"));
+            let b = Plugin::load(&dir.join("plugin_b.so"));
+
+            let hello = a.intern("hello");
+            assert_eq!(b.intern("hello"), hello);
+            assert_eq!(b.existing("hello"), hello);
+            assert!(a.existing("world").is_null());
+            assert_eq!(b.intern("world"), a.intern("world"));
+            assert_eq!((a.num_entries)(), 2);
+            assert_eq!((b.num_entries)(), 2);
+
+            // A string counted by one plugin is freed by the other. Looking
+            // it up as a `Ustr` would make it immortal, so only count it.
+            let arc = unsafe { (a.arc)("transient".as_ptr(), 9) };
+            assert_eq!((b.num_entries)(), 3);
+            unsafe { (b.drop_arc)(arc) };
+            assert_eq!((a.num_entries)(), 2);
+            return;
+        }
+
+        // Build the plugin, and copy it so that it's loaded twice, each copy
+        // with its own copy of ustr.
+        let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+        let target = root.join("target").join("dylib-plugin");
+        let status = Command::new(
+            std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into()),
+        )
+        .args(["build", "--quiet", "--manifest-path"])
+        .arg(root.join("tests/dylib-plugin/Cargo.toml"))
+        .arg("--target-dir")
+        .arg(&target)
+        .status()
+        .unwrap();
+        assert!(status.success());
+        let built = target.join("debug/libustr_dylib_plugin.so");
+        for name in ["plugin_a.so", "plugin_b.so"] {
+            std::fs::copy(&built, target.join(name)).unwrap();
+        }
+
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "dylib::tests::test_plugins"])
+            .arg("--nocapture")
+            .env(CHILD, &target)
+            .status()
+            .unwrap();
+        assert!(status.success());
+    }
+
+    #[test]
+    fn test_check() {
+        let _t = crate::TEST_LOCK.lock();
+
+        // A program linking ustr once owns its cache, whether or not its
+        // executable exports the symbol.
+        assert!(owner().is_none());
+        if let Some(reg) = first_registration() {
+            assert_eq!(reg, ustr_global_cache());
+        }
+
+        let current = Registration::current();
+        assert_eq!(unsafe { Registration::check(&current) }, Ok(()));
+        let other = Registration {
+            version: VERSION + 1,
+            ..Registration::current()
+        };
+        assert_eq!(
+            unsafe { Registration::check(&other) },
+            Err(Incompatible::Version(VERSION + 1))
+        );
+        let other = Registration {
+            entry_size: current.entry_size + 8,
+            ..Registration::current()
+        };
+        assert_eq!(
+            unsafe { Registration::check(&other) },
+            Err(Incompatible::Layout)
+        );
+        let other = Registration {
+            hash_check: !current.hash_check,
+            ..Registration::current()
+        };
+        assert_eq!(
+            unsafe { Registration::check(&other) },
+            Err(Incompatible::Hash)
+        );
+
+        for e in [
+            InternError::StringTooLong { len: 12, max: 8 },
+            InternError::TooManyEntries { max: 8 },
+            InternError::TooManyBytes { max: 8 },
+            InternError::OutOfMemory,
+            InternError::Frozen,
+        ] {
+            assert_eq!(RawError::new(e.clone()).to_error(), e);
+        }
+    }
+}
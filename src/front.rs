@@ -0,0 +1,230 @@
+use crate::{Ustr, sync::Mutex};
+use alloc::{sync::Arc, vec::Vec};
+#[cfg(feature = "std")]
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+
+// Each thread can keep a small direct-mapped cache of the `Ustr`s it created
+// most recently, in front of the global cache. A slot is picked by the low
+// bits of the string's hash and holds the last `Ustr` whose hash mapped
+// there, so a hit costs a hash, a comparison with the string and no access to
+// `STRING_CACHE` at all. The cache is off by default, and there are no
+// thread-locals to keep it in without the standard library.
+
+// Number of slots in each thread's front cache, 0 meaning disabled.
+static SIZE: AtomicUsize = AtomicUsize::new(0);
+// Bumped to make every thread drop what's in its front cache.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+// Hit and miss counts of every thread that has used its front cache.
+static REGISTRY: Mutex<Registry> = Mutex::new(Registry {
+    threads: Vec::new(),
+    exited: FrontCacheStats { hits: 0, misses: 0 },
+});
+
+struct Registry {
+    threads: Vec<Arc<Counters>>,
+    // Counts of threads that have exited.
+    exited: FrontCacheStats,
+}
+
+// Only ever written by the thread owning them, so updating them doesn't need
+// atomic read-modify-write operations.
+#[derive(Default)]
+struct Counters {
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl Counters {
+    #[cfg(feature = "std")]
+    #[inline]
+    fn bump(counter: &AtomicU64) {
+        counter.store(counter.load(Ordering::Relaxed) + 1, Ordering::Relaxed);
+    }
+
+    fn stats(&self) -> FrontCacheStats {
+        FrontCacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+struct FrontCache {
+    // Each slot holds the hash of its string, so slots holding another string
+    // are skipped without reading the string itself.
+    slots: Vec<(u64, Option<Ustr>)>,
+    generation: u64,
+    counters: Option<Arc<Counters>>,
+}
+
+#[cfg(feature = "std")]
+thread_local! {
+    static FRONT: RefCell<FrontCache> = const {
+        RefCell::new(FrontCache {
+            slots: Vec::new(),
+            generation: 0,
+            counters: None,
+        })
+    };
+}
+
+#[cfg(feature = "std")]
+impl FrontCache {
+    // Get the cache ready for use with `size` slots, dropping what's in it if
+    // the size changed or the front caches were cleared.
+    fn prepare(&mut self, size: usize) {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if self.slots.len() != size || self.generation != generation {
+            self.slots.clear();
+            self.slots.resize(size, (0, None));
+            self.generation = generation;
+        }
+        if self.counters.is_none() {
+            let counters = Arc::new(Counters::default());
+            REGISTRY.lock().threads.push(counters.clone());
+            self.counters = Some(counters);
+        }
+    }
+
+    #[inline]
+    fn record(&self, hit: bool) {
+        if let Some(counters) = &self.counters {
+            Counters::bump(if hit {
+                &counters.hits
+            } else {
+                &counters.misses
+            });
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Drop for FrontCache {
+    fn drop(&mut self) {
+        if let Some(counters) = self.counters.take() {
+            let mut registry = REGISTRY.lock();
+            registry.threads.retain(|c| !Arc::ptr_eq(c, &counters));
+            let stats = counters.stats();
+            registry.exited.hits += stats.hits;
+            registry.exited.misses += stats.misses;
+        }
+    }
+}
+
+// Look for `string` in this thread's front cache.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn get(string: &str, hash: u64) -> Option<Ustr> {
+    let size = SIZE.load(Ordering::Relaxed);
+    if size == 0 {
+        return None;
+    }
+    // There's no front cache to use while the thread is exiting.
+    FRONT
+        .try_with(|front| {
+            let mut front = front.borrow_mut();
+            front.prepare(size);
+            let (slot_hash, u) = front.slots[hash as usize & (size - 1)];
+            let hit = u.filter(|u| slot_hash == hash && u.as_str() == string);
+            front.record(hit.is_some());
+            hit
+        })
+        .ok()
+        .flatten()
+}
+
+#[cfg(not(feature = "std"))]
+#[inline]
+pub(crate) fn get(_string: &str, _hash: u64) -> Option<Ustr> {
+    None
+}
+
+// Remember `u` in this thread's front cache.
+#[cfg(feature = "std")]
+#[inline]
+pub(crate) fn put(u: Ustr) {
+    let size = SIZE.load(Ordering::Relaxed);
+    if size == 0 {
+        return;
+    }
+    let _ = FRONT.try_with(|front| {
+        let mut front = front.borrow_mut();
+        front.prepare(size);
+        let hash = u.precomputed_hash();
+        front.slots[hash as usize & (size - 1)] = (hash, Some(u));
+    });
+}
+
+#[cfg(not(feature = "std"))]
+#[inline]
+pub(crate) fn put(_u: Ustr) {}
+
+/// Hit and miss counts of the thread-local front caches, summed over all
+/// threads, see [`set_front_cache_size()`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FrontCacheStats {
+    /// Number of strings found in a front cache.
+    pub hits: u64,
+    /// Number of strings that had to be looked up in the global cache.
+    pub misses: u64,
+}
+
+/// Set the number of slots in each thread's front cache, or disable the front
+/// caches with 0, which is the default.
+///
+/// With a front cache, each thread remembers the `Ustr`s it created most
+/// recently, so interning one of them again only costs hashing and comparing
+/// the string, without looking it up in the global cache. This pays off for
+/// programs that intern the same few thousand strings over and over, such as
+/// the identifiers seen by a parser. The size is rounded up to a power of two,
+/// and each slot takes 16 bytes per thread.
+///
+/// Threads pick up the new size, emptying their front cache, the next time
+/// they intern a string. Without the `std` feature, there are no front caches
+/// and the size has no effect.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::ustr;
+///
+/// ustr::set_front_cache_size(4096);
+/// let hello = ustr("hello");
+/// assert_eq!(ustr("hello"), hello);
+/// assert_eq!(ustr::front_cache_stats().hits, 1);
+/// ```
+pub fn set_front_cache_size(slots: usize) {
+    let size = match slots {
+        0 => 0,
+        n => n
+            .checked_next_power_of_two()
+            .unwrap_or(1 << (usize::BITS - 1)),
+    };
+    SIZE.store(size, Ordering::Relaxed);
+}
+
+/// Returns the number of slots in each thread's front cache, 0 if the front
+/// caches are disabled.
+pub fn front_cache_size() -> usize {
+    SIZE.load(Ordering::Relaxed)
+}
+
+/// Empty the front cache of every thread. Each thread drops its entries the
+/// next time it interns a string.
+pub fn clear_front_cache() {
+    GENERATION.fetch_add(1, Ordering::Release);
+}
+
+/// Returns the hit and miss counts of the front caches of all threads.
+pub fn front_cache_stats() -> FrontCacheStats {
+    let registry = REGISTRY.lock();
+    registry.threads.iter().map(|c| c.stats()).fold(
+        registry.exited,
+        |total, stats| FrontCacheStats {
+            hits: total.hits + stats.hits,
+            misses: total.misses + stats.misses,
+        },
+    )
+}
@@ -1,9 +1,10 @@
 use super::Ustr;
 use byteorder::{ByteOrder, NativeEndian};
-use std::{
-    collections::{HashMap, HashSet},
-    hash::{BuildHasherDefault, Hasher},
-};
+use core::hash::{BuildHasherDefault, Hasher};
+#[cfg(not(feature = "std"))]
+use hashbrown::{HashMap, HashSet};
+#[cfg(feature = "std")]
+use std::collections::{HashMap, HashSet};
 
 /// A const-compatible hash function using FNV-1a algorithm.
 /// This can be evaluated at compile time when used with string literals.
@@ -86,10 +87,14 @@ macro_rules! const_hash {
 
 /// A standard `HashMap` using `Ustr` as the key type with a custom `Hasher`
 /// that just uses the precomputed hash for speed instead of calculating it.
+///
+/// Without the `std` feature, this is a `hashbrown::HashMap`.
 pub type UstrMap<V> = HashMap<Ustr, V, BuildHasherDefault<IdentityHasher>>;
 
 /// A standard `HashSet` using `Ustr` as the key type with a custom `Hasher`
 /// that just uses the precomputed hash for speed instead of calculating it.
+///
+/// Without the `std` feature, this is a `hashbrown::HashSet`.
 pub type UstrSet = HashSet<Ustr, BuildHasherDefault<IdentityHasher>>;
 
 /// The worst hasher in the world -- the identity hasher.
@@ -135,7 +140,7 @@ fn test_hashing() {
     let _t = super::TEST_LOCK.lock();
     use crate::ustr as u;
 
-    use std::hash::Hash;
+    use core::hash::Hash;
     let u1 = u("the quick brown fox");
     let u2 = u("jumped over the lazy dog");
 
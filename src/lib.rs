@@ -180,6 +180,17 @@
 //! use it on 32-bit, please make sure to run Miri and open and issue if you
 //! find any problems.
 //!
+//! Each copy of ustr linked into a process has its own global cache, so `Ustr`s
+//! made by two cdylib plugins that each link ustr statically don't compare
+//! equal. With the `dylib` feature (Linux only), every copy built with it uses
+//! the cache of the first loaded object that exports one, which must then stay
+//! loaded. Creating `Ustr`s and `ArcUstr`s and [`num_entries()`] go through
+//! that copy, while the other functions working on the whole cache, such as
+//! [`string_cache_iter()`], [`freeze()`], [`set_budget()`] or [`configure()`],
+//! only see the cache of the copy they are called from. A copy panics on first
+//! use if the owner's version, entry layout or hash function differs from its
+//! own.
+//!
 //! ## Performance Characteristics
 //!
 //! ### Hash Function Selection
@@ -196,6 +207,11 @@
 //! hashing is only about 2% of the total time for string interning. The real
 //! bottlenecks are:
 //! 1. **Mutex locking** for thread-safe cache access (~20-30 ns) - 40% of time.
+//!    Only strings that aren't in the cache yet pay for this: looking up a
+//!    string that a [`Ustr`] was created for never takes a lock. Lookups that
+//!    don't find a `Ustr`'s string do take the lock if they passed over an
+//!    [`ArcUstr`]'s string on the way, which may be freed at any time and so
+//!    can only be compared with the lock held.
 //! 2. **Hash table lookup and insertion** (~10-15 ns) - 30% of time.
 //! 3. **Memory allocation** for new strings (~5-10 ns) - 20% of time.
 //! 4. **String hashing** (~1 ns) - 2% of time.
@@ -207,37 +223,73 @@
 //!
 //! ## Features
 #![doc = document_features::document_features!()]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+extern crate alloc;
 
-use parking_lot::Mutex;
-use std::{
-    borrow::Cow,
+use alloc::{
+    borrow::{Cow, ToOwned},
+    boxed::Box,
+    rc::Rc,
+    string::String,
+    sync::Arc,
+    vec::Vec,
+};
+use core::{
     cmp::Ordering,
-    ffi::{CStr, OsStr},
+    ffi::{CStr, c_char},
     fmt,
     hash::{Hash, Hasher},
     ops::Deref,
-    os::raw::c_char,
-    path::Path,
     ptr::NonNull,
-    rc::Rc,
     slice, str,
     str::FromStr,
-    sync::Arc,
 };
+#[cfg(feature = "std")]
+use std::{ffi::OsStr, path::Path};
 
+mod arcustr;
+pub use arcustr::ArcUstr;
+mod budget;
+pub use budget::{Budget, InternError, budget, set_budget};
 mod bumpalloc;
 pub mod cache;
 pub use cache::*;
+mod config;
+pub use config::{CacheConfig, ConfigError, FrozenPolicy, configure};
+#[cfg(all(feature = "dylib", target_os = "linux"))]
+mod dylib;
+mod front;
+pub use front::{
+    FrontCacheStats, clear_front_cache, front_cache_size, front_cache_stats,
+    set_front_cache_size,
+};
 pub mod hash;
 pub use hash::{UstrMap, UstrSet};
+mod local;
+pub use local::{LocalInterner, LocalUstr};
+#[cfg(all(feature = "mmap", unix))]
+mod persist;
+#[cfg(all(feature = "mmap", unix))]
+pub use persist::{PersistError, open_persistent};
+mod profile;
+#[cfg(all(feature = "shm", target_os = "linux"))]
+mod shm;
+#[cfg(all(feature = "shm", target_os = "linux"))]
+pub use shm::{SharedCache, SharedError};
 mod stringcache;
 pub use stringcache::*;
 #[cfg(feature = "serde")]
 pub mod serialization;
+mod sync;
+#[cfg(feature = "std")]
+mod tag;
 #[cfg(feature = "facet")]
 pub use facet::Facet;
 #[cfg(feature = "serde")]
-pub use serialization::DeserializedCache;
+pub use serialization::{DeserializedCache, ExistingUstr};
+#[cfg(feature = "std")]
+pub use tag::with_tag;
 
 #[cfg(feature = "rkyv")]
 use rkyv::{
@@ -296,25 +348,133 @@ impl Ustr {
     /// assert_eq!(u1, u2);
     /// assert_eq!(ustr::num_entries(), 1);
     /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string is not in the cache yet and can't be added, e.g.
+    /// because the [`Budget`] is exhausted. Use [`Ustr::try_from_str`] to
+    /// handle this case.
     pub fn from(string: &str) -> Ustr {
         // Use the unified hash function which will be optimized appropriately
-        let hash = crate::hash::hash(string.as_bytes());
-        let mut sc = STRING_CACHE.0[whichbin(hash)].lock();
-        Ustr {
+        Ustr::from_prehashed(string, crate::hash::hash(string.as_bytes()))
+    }
+
+    /// Create a new `Ustr` from the given `str` and its hash, as computed by
+    /// [`hash::hash()`](crate::hash::hash).
+    ///
+    /// This saves hashing the string again in pipelines that already have
+    /// the hash at hand. Passing any other hash breaks the guarantee that
+    /// equal strings give equal `Ustr`s, and is caught by a debug assertion.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::{Ustr, ustr};
+    ///
+    /// let hash = ustr::hash::hash(b"column");
+    /// assert_eq!(Ustr::from_prehashed("column", hash), ustr("column"));
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be added to the cache, like
+    /// [`Ustr::from`].
+    pub fn from_prehashed(string: &str, hash: u64) -> Ustr {
+        debug_assert_eq!(
+            hash,
+            crate::hash::hash(string.as_bytes()),
+            "wrong hash given for {string:?}"
+        );
+        if let Some(u) = front::get(string, hash) {
+            return profile::interned(u);
+        }
+        #[cfg(all(feature = "dylib", target_os = "linux"))]
+        if let Some(owner) = dylib::owner() {
+            let ptr = owner
+                .intern(string, hash)
+                .unwrap_or_else(|e| panic!("failed to intern string: {e}"));
+            let u = Ustr {
+                char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+            };
+            front::put(u);
+            return profile::interned(u);
+        }
+        let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+        let u = Ustr {
             // SAFETY: sc.insert does not give back a null pointer
             char_ptr: unsafe {
                 NonNull::new_unchecked(sc.insert(string, hash) as *mut _)
             },
+        };
+        front::put(u);
+        profile::interned(u)
+    }
+
+    /// Create a new `Ustr` from the given `str`, or return an error if the
+    /// string is not in the cache yet and can't be added.
+    ///
+    /// Strings are rejected when they would exceed the limits set with
+    /// [`set_budget()`], or when memory for them can't be allocated.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::{Budget, InternError, Ustr};
+    ///
+    /// ustr::set_budget(Budget {
+    ///     max_string_len: Some(8),
+    ///     ..Budget::default()
+    /// });
+    ///
+    /// assert!(Ustr::try_from_str("short").is_ok());
+    /// assert_eq!(
+    ///     Ustr::try_from_str("far too long"),
+    ///     Err(InternError::StringTooLong { len: 12, max: 8 })
+    /// );
+    /// ```
+    pub fn try_from_str(string: &str) -> Result<Ustr, InternError> {
+        let hash = crate::hash::hash(string.as_bytes());
+        if let Some(u) = front::get(string, hash) {
+            return Ok(profile::interned(u));
+        }
+        #[cfg(all(feature = "dylib", target_os = "linux"))]
+        if let Some(owner) = dylib::owner() {
+            let ptr = owner.intern(string, hash)?;
+            let u = Ustr {
+                char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+            };
+            front::put(u);
+            return Ok(profile::interned(u));
         }
+        let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+        let u = sc.try_insert(string, hash).map(|ptr| Ustr {
+            // SAFETY: sc.try_insert does not give back a null pointer
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        })?;
+        front::put(u);
+        Ok(profile::interned(u))
     }
 
     pub fn from_existing(string: &str) -> Option<Ustr> {
         // Use the unified hash function
         let hash = crate::hash::hash(string.as_bytes());
-        let sc = STRING_CACHE.0[whichbin(hash)].lock();
-        sc.get_existing(string, hash).map(|ptr| Ustr {
+        if let Some(u) = front::get(string, hash) {
+            return Some(profile::looked_up(u));
+        }
+        #[cfg(all(feature = "dylib", target_os = "linux"))]
+        if let Some(owner) = dylib::owner() {
+            let u = owner.get_existing(string, hash).map(|ptr| Ustr {
+                char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+            })?;
+            front::put(u);
+            return Some(profile::looked_up(u));
+        }
+        let sc = &STRING_CACHE.0[STRING_CACHE.whichbin(hash)];
+        let u = sc.get_existing(string, hash).map(|ptr| Ustr {
             char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
-        })
+        })?;
+        front::put(u);
+        Some(profile::looked_up(u))
     }
 
     /// Get the cached `Ustr` as a `str`.
@@ -531,24 +691,28 @@ impl PartialEq<Ustr> for &Cow<'_, str> {
     }
 }
 
+#[cfg(feature = "std")]
 impl PartialEq<Ustr> for Path {
     fn eq(&self, u: &Ustr) -> bool {
         self == Path::new(u)
     }
 }
 
+#[cfg(feature = "std")]
 impl PartialEq<Ustr> for &Path {
     fn eq(&self, u: &Ustr) -> bool {
         *self == Path::new(u)
     }
 }
 
+#[cfg(feature = "std")]
 impl PartialEq<Ustr> for OsStr {
     fn eq(&self, u: &Ustr) -> bool {
         self == OsStr::new(u)
     }
 }
 
+#[cfg(feature = "std")]
 impl PartialEq<Ustr> for &OsStr {
     fn eq(&self, u: &Ustr) -> bool {
         *self == OsStr::new(u)
@@ -567,7 +731,7 @@ where
 }
 
 impl FromStr for Ustr {
-    type Err = std::string::ParseError;
+    type Err = alloc::string::ParseError;
 
     #[inline]
     fn from_str(s: &str) -> Result<Self, Self::Err> {
@@ -742,6 +906,191 @@ pub fn ustr(s: &str) -> Ustr {
     Ustr::from(s)
 }
 
+/// Intern every string in `strings`, returning their `Ustr`s in the same
+/// order.
+///
+/// This is faster than calling [`ustr()`] on each string when many of them
+/// are new: strings that are already interned are found without locking as
+/// usual, while the new ones are grouped by the bin they belong to, so each
+/// bin's lock is taken at most once per few thousand strings.
+///
+/// # Examples
+///
+/// ```
+/// let column = ["red", "green", "red", "blue"];
+/// let interned = ustr::intern_many(column);
+/// assert_eq!(interned, column);
+/// assert_eq!(interned[0], interned[2]);
+/// ```
+///
+/// # Panics
+///
+/// Panics if a string can't be added to the cache, like [`Ustr::from`].
+pub fn intern_many<'a>(
+    strings: impl IntoIterator<Item = &'a str>,
+) -> Vec<Ustr> {
+    let mut out = Vec::new();
+    extend_into(strings, &mut out);
+    out
+}
+
+/// Intern every string in `strings` like [`intern_many()`], appending their
+/// `Ustr`s to `out`.
+///
+/// This lets a buffer be reused from one batch to the next.
+///
+/// # Panics
+///
+/// Panics if a string can't be added to the cache, like [`Ustr::from`].
+pub fn extend_into<'a>(
+    strings: impl IntoIterator<Item = &'a str>,
+    out: &mut Vec<Ustr>,
+) {
+    // Work through the strings in chunks, so that a huge batch doesn't need
+    // a lot of extra memory and each chunk stays in cache.
+    const CHUNK: usize = 4096;
+    let mut strings = strings.into_iter();
+    // Another copy of ustr owns the cache, and takes the strings one by one.
+    #[cfg(all(feature = "dylib", target_os = "linux"))]
+    if dylib::owner().is_some() {
+        out.extend(strings.map(Ustr::from));
+        return;
+    }
+    let mut chunk: Vec<(&str, u64)> = Vec::with_capacity(CHUNK);
+    let mut chars: Vec<*const u8> = Vec::with_capacity(CHUNK);
+    // Strings that aren't in the cache yet, as (bin, index in the chunk).
+    let mut missing: Vec<(usize, usize)> = Vec::new();
+    loop {
+        chunk.clear();
+        chunk.extend(
+            strings
+                .by_ref()
+                .take(CHUNK)
+                .map(|s| (s, crate::hash::hash(s.as_bytes()))),
+        );
+        if chunk.is_empty() {
+            break;
+        }
+
+        // Most strings are usually in the cache already and can be found
+        // without any lock.
+        chars.clear();
+        missing.clear();
+        for (i, &(string, hash)) in chunk.iter().enumerate() {
+            let bin = STRING_CACHE.whichbin(hash);
+            let ptr = STRING_CACHE.0[bin].get_unlocked(string, hash);
+            if ptr.is_none() {
+                missing.push((bin, i));
+            }
+            chars.push(ptr.unwrap_or(core::ptr::null()));
+        }
+
+        // Take the lock of each bin once for all its new strings.
+        missing.sort_unstable();
+        for batch in missing.chunk_by(|a, b| a.0 == b.0) {
+            STRING_CACHE.0[batch[0].0].insert_batch(
+                batch.iter().map(|&(_, i)| (i, chunk[i].0, chunk[i].1)),
+                |i, ptr| chars[i] = ptr,
+            );
+        }
+
+        out.extend(chars.iter().map(|&ptr| {
+            profile::interned(Ustr {
+                // SAFETY: every string was found or inserted, and neither
+                // gives back a null pointer
+                char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+            })
+        }));
+    }
+}
+
+/// Intern every string in `strings` on the `rayon` thread pool, returning
+/// their `Ustr`s in the same order.
+///
+/// The strings are hashed and looked up without locking in parallel. The new
+/// ones are then grouped by the bin they belong to and each bin's group is
+/// inserted by a single task, so the tasks take disjoint locks and never wait
+/// on each other.
+///
+/// # Examples
+///
+/// ```
+/// use rayon::prelude::*;
+///
+/// let lines = vec!["GET /", "POST /login", "GET /"];
+/// let interned = ustr::par_intern(lines.par_iter().copied());
+/// assert_eq!(interned, lines);
+/// assert_eq!(interned[0], interned[2]);
+/// ```
+///
+/// # Panics
+///
+/// Panics if a string can't be added to the cache, like [`Ustr::from`].
+#[cfg(feature = "rayon")]
+pub fn par_intern<'a>(
+    strings: impl rayon::iter::IntoParallelIterator<Item = &'a str>,
+) -> Vec<Ustr> {
+    use rayon::prelude::*;
+
+    // Neither lookups nor inserts give back a null pointer.
+    fn ustr_from_char_ptr(ptr: *const u8) -> Ustr {
+        Ustr {
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+        }
+    }
+
+    // Another copy of ustr owns the cache, and takes the strings one by one.
+    #[cfg(all(feature = "dylib", target_os = "linux"))]
+    if dylib::owner().is_some() {
+        return strings.into_par_iter().map(Ustr::from).collect();
+    }
+
+    let mut found: Vec<(&str, u64, Option<Ustr>)> = strings
+        .into_par_iter()
+        .map(|string| {
+            let hash = crate::hash::hash(string.as_bytes());
+            let bin = STRING_CACHE.whichbin(hash);
+            let ptr = STRING_CACHE.0[bin].get_unlocked(string, hash);
+            (string, hash, ptr.map(ustr_from_char_ptr))
+        })
+        .collect();
+
+    // Strings that aren't in the cache yet, as (bin, index in `found`).
+    let mut missing: Vec<(usize, usize)> = found
+        .par_iter()
+        .enumerate()
+        .filter(|(_, (_, _, u))| u.is_none())
+        .map(|(i, &(_, hash, _))| (STRING_CACHE.whichbin(hash), i))
+        .collect();
+    missing.par_sort_unstable();
+
+    // The new strings are added on other threads, under the tag of this one.
+    let tag = tag::current();
+    let inserted: Vec<(usize, Ustr)> = missing
+        .par_chunk_by(|a, b| a.0 == b.0)
+        .flat_map_iter(|batch| {
+            let mut inserted = Vec::with_capacity(batch.len());
+            tag::with_current(tag, || {
+                STRING_CACHE.0[batch[0].0].insert_batch(
+                    batch.iter().map(|&(_, i)| (i, found[i].0, found[i].1)),
+                    |i, ptr| inserted.push((i, ustr_from_char_ptr(ptr))),
+                )
+            });
+            inserted
+        })
+        .collect();
+    for (i, u) in inserted {
+        found[i].2 = Some(u);
+    }
+
+    found
+        .into_par_iter()
+        .map(|(_, _, u)| {
+            profile::interned(u.expect("every string was found or inserted"))
+        })
+        .collect()
+}
+
 /// Create a new `Ustr` from the given `str` but only if it already exists in
 /// the string cache.
 ///
@@ -807,9 +1156,11 @@ macro_rules! static_ustr {
     }};
 }
 
+static STRING_CACHE: sync::Lazy<Bins> = sync::Lazy::new(Bins::init);
+
 #[cfg(test)]
 lazy_static::lazy_static! {
-    static ref TEST_LOCK: Mutex<()> = Mutex::new(());
+    static ref TEST_LOCK: parking_lot::Mutex<()> = parking_lot::Mutex::new(());
 }
 
 #[cfg(test)]
@@ -827,7 +1178,7 @@ mod tests {
         assert_eq!(super::Ustr::SHAPE.type_identifier, "Ustr");
         assert_eq!(
             super::Ustr::SHAPE.layout.sized_layout().unwrap().size(),
-            std::mem::size_of::<super::Ustr>()
+            core::mem::size_of::<super::Ustr>()
         );
     }
 
@@ -862,7 +1213,7 @@ mod tests {
     fn c_str_works() {
         let _t = TEST_LOCK.lock();
         use super::ustr as u;
-        use std::ffi::CStr;
+        use core::ffi::CStr;
 
         let s_fox = "The quick brown fox jumps over the lazy dog.";
         let u_fox = u(s_fox);
@@ -913,7 +1264,7 @@ mod tests {
 
         let mut hs_u = HashSet::new();
         for s in string_cache_iter() {
-            hs_u.insert(s);
+            hs_u.insert(s.as_str());
         }
         let diff: HashSet<_> = hs.difference(&hs_u).collect();
 
@@ -932,7 +1283,7 @@ mod tests {
 
         println!(
             "size of StringCache: {}",
-            std::mem::size_of::<super::StringCache>()
+            core::mem::size_of::<super::StringCache>()
         );
     }
 
@@ -1038,7 +1389,7 @@ mod tests {
         // now check that we've got the same data in the cache still
         let mut hs_u = HashSet::new();
         for s in string_cache_iter() {
-            hs_u.insert(s);
+            hs_u.insert(s.as_str());
         }
         let diff: HashSet<_> = hs.difference(&hs_u).collect();
 
@@ -1065,6 +1416,57 @@ mod tests {
         assert_eq!(u_hello, me_hello);
     }
 
+    #[cfg(all(feature = "serde", not(miri)))]
+    #[test]
+    fn serialization_existing() {
+        let _t = TEST_LOCK.lock();
+
+        use super::{ExistingUstr, Ustr, UstrSet, num_entries, ustr};
+        use std::collections::HashMap;
+
+        unsafe { super::_clear_cache() };
+
+        let vocab = ["red", "green", "blue"].map(ustr);
+
+        let color: ExistingUstr = serde_json::from_str("\"green\"").unwrap();
+        assert_eq!(color, vocab[1]);
+        assert_eq!(serde_json::to_string(&color).unwrap(), "\"green\"");
+
+        let err =
+            serde_json::from_str::<ExistingUstr>("\"mauve\"").unwrap_err();
+        assert!(err.to_string().contains("\"mauve\""), "{err}");
+
+        // Maps and sets keyed by `ExistingUstr`.
+        let counts: HashMap<ExistingUstr, u32> =
+            serde_json::from_str(r#"{"red": 1, "blue": 2}"#).unwrap();
+        assert_eq!(counts[&ExistingUstr(vocab[2])], 2);
+        assert!(
+            serde_json::from_str::<HashMap<ExistingUstr, u32>>(
+                r#"{"red": 1, "cyan": 2}"#
+            )
+            .is_err()
+        );
+        let set: std::collections::HashSet<
+            ExistingUstr,
+            core::hash::BuildHasherDefault<super::hash::IdentityHasher>,
+        > = serde_json::from_str(r#"["red", "green", "red"]"#).unwrap();
+        assert_eq!(set.len(), 2);
+        let set: UstrSet = set.into_iter().map(|u| u.0).collect();
+        assert!(set.contains(&vocab[0]));
+
+        #[derive(serde::Deserialize)]
+        struct Pixel {
+            #[serde(with = "crate::serialization::existing")]
+            color: Ustr,
+        }
+        let p: Pixel = serde_json::from_str(r#"{"color": "blue"}"#).unwrap();
+        assert_eq!(p.color, vocab[2]);
+        assert!(serde_json::from_str::<Pixel>(r#"{"color": "teal"}"#).is_err());
+
+        // Nothing was interned along the way.
+        assert_eq!(num_entries(), 3);
+    }
+
     #[cfg(all(feature = "rkyv", not(miri)))]
     #[test]
     fn rkyv_ustr() {
@@ -1165,12 +1567,565 @@ mod tests {
         assert_eq!(Some(s1), s2);
     }
 
+    #[test]
+    fn configure_after_init() {
+        let _t = TEST_LOCK.lock();
+        use super::{CacheConfig, ConfigError, configure, ustr};
+
+        assert_eq!(
+            configure(CacheConfig {
+                bins: 3,
+                ..CacheConfig::default()
+            }),
+            Err(ConfigError::InvalidBins(3))
+        );
+
+        let _ = ustr("configured");
+        assert_eq!(
+            configure(CacheConfig::default()),
+            Err(ConfigError::AlreadyInitialized)
+        );
+    }
+
+    #[test]
+    fn arc_ustr() {
+        let _t = TEST_LOCK.lock();
+        use super::{ArcUstr, existing_ustr, num_entries, ustr};
+
+        unsafe { super::_clear_cache() };
+
+        let a1 = ArcUstr::from("transient");
+        let a2 = ArcUstr::from_existing("transient").unwrap();
+        assert_eq!(a1, a2);
+        assert_eq!(a1.as_cstr().to_str().unwrap(), "transient");
+        assert_eq!(
+            a1.precomputed_hash(),
+            crate::hash::hash("transient".as_bytes())
+        );
+        assert_eq!(num_entries(), 1);
+        // Counted entries aren't handed out as `&'static str`s.
+        assert_eq!(super::string_cache_iter().count(), 0);
+
+        let a3 = a1.clone();
+        drop(a1);
+        drop(a2);
+        assert_eq!(num_entries(), 1);
+        drop(a3);
+        assert_eq!(num_entries(), 0);
+        assert_eq!(ArcUstr::from_existing("transient"), None);
+
+        // Lots of short-lived strings reuse the tombstoned slots rather than
+        // growing the cache.
+        for i in 0..10_000 {
+            let a = ArcUstr::from(format!("transient {i}").as_str());
+            assert_eq!(a, format!("transient {i}").as_str());
+        }
+        assert_eq!(num_entries(), 0);
+        assert_eq!(super::total_allocated(), 0);
+
+        // Making a `Ustr` pins the string forever.
+        let a = ArcUstr::from("pinned");
+        let u = ustr("pinned");
+        assert_eq!(a, u);
+        drop(a);
+        assert_eq!(existing_ustr("pinned"), Some(u));
+        let a = ArcUstr::from("pinned");
+        assert_eq!(a, u);
+        drop(a);
+        assert_eq!(num_entries(), 1);
+        assert_eq!(super::string_cache_iter().collect::<Vec<_>>(), ["pinned"]);
+    }
+
+    // Uses the cache from several threads at once.
+    #[cfg(not(feature = "single-thread"))]
+    #[test]
+    #[cfg_attr(miri, ignore)]
+    fn arc_ustr_threads() {
+        let _t = TEST_LOCK.lock();
+        use super::{ArcUstr, num_entries};
+
+        unsafe { super::_clear_cache() };
+
+        std::thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    for i in 0..2_000 {
+                        let a = ArcUstr::from(
+                            format!("shared {}", i % 16).as_str(),
+                        );
+                        let b = a.clone();
+                        assert_eq!(a, b);
+                    }
+                });
+            }
+        });
+        assert_eq!(num_entries(), 0);
+    }
+
+    #[test]
+    fn freeze() {
+        let _t = TEST_LOCK.lock();
+        use super::{ArcUstr, InternError, STRING_CACHE, Ustr, ustr as u};
+        use std::{sync::mpsc, time::Duration};
+
+        unsafe { super::_clear_cache() };
+
+        let words: Vec<String> =
+            (0..10_000).map(|i| format!("frozen{i}")).collect();
+        let ustrs: Vec<Ustr> = words.iter().map(|w| u(w)).collect();
+        let counted = ArcUstr::from("counted");
+        assert!(!super::is_frozen());
+
+        super::freeze();
+        assert!(super::is_frozen());
+        assert_eq!(super::num_entries(), words.len() + 1);
+
+        // Lookups don't need any lock, not even for the counted entry, which
+        // is now immortal.
+        let guards: Vec<_> =
+            STRING_CACHE.0.iter().map(|sc| sc.lock()).collect();
+        let (tx, rx) = mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for (w, us) in words.iter().zip(&ustrs) {
+                    assert_eq!(Ustr::from_existing(w), Some(*us));
+                    assert_eq!(u(w), *us);
+                }
+                assert_eq!(ArcUstr::from_existing("counted").unwrap(), counted);
+                assert_eq!(Ustr::from_existing("missing"), None);
+                tx.send(()).unwrap();
+            });
+            let res = rx.recv_timeout(Duration::from_secs(10));
+            drop(guards);
+            res.expect("lookup in a frozen cache blocked on the lock");
+        });
+        drop(counted);
+        assert_eq!(Ustr::from_existing("counted").unwrap(), "counted");
+
+        assert_eq!(Ustr::try_from_str("missing"), Err(InternError::Frozen));
+        assert_eq!(ArcUstr::try_from_str("missing"), Err(InternError::Frozen));
+        assert!(std::panic::catch_unwind(|| u("missing")).is_err());
+        assert_eq!(super::num_entries(), words.len() + 1);
+
+        unsafe { super::_clear_cache() };
+        assert!(!super::is_frozen());
+        assert_eq!(u("missing"), "missing");
+    }
+
+    #[test]
+    fn front_cache() {
+        let _t = TEST_LOCK.lock();
+        use super::{Ustr, ustr as u};
+        unsafe { super::_clear_cache() };
+
+        // A single slot makes every string collide.
+        super::set_front_cache_size(1);
+        assert_eq!(super::front_cache_size(), 1);
+        let before = super::front_cache_stats();
+        let hello = u("hello");
+        let world = u("world");
+        assert_eq!(u("world"), world);
+        assert_eq!(Ustr::from_existing("world"), Some(world));
+        assert_eq!(u("hello"), hello);
+        assert_eq!(Ustr::from_existing("missing"), None);
+        let stats = super::front_cache_stats();
+        assert_eq!(stats.hits - before.hits, 2);
+        assert_eq!(stats.misses - before.misses, 4);
+
+        // Other threads have their own front cache, and their counts stay
+        // once they exit.
+        std::thread::spawn(|| {
+            for _ in 0..10 {
+                assert_eq!(u("threaded"), "threaded");
+            }
+        })
+        .join()
+        .unwrap();
+        let stats = super::front_cache_stats();
+        assert_eq!(stats.hits - before.hits, 11);
+        assert_eq!(stats.misses - before.misses, 5);
+
+        super::set_front_cache_size(1000);
+        assert_eq!(super::front_cache_size(), 1024);
+        assert_eq!(u("hello"), hello);
+        assert_eq!(u("hello"), hello);
+        super::clear_front_cache();
+        assert_eq!(u("hello"), hello);
+        let stats = super::front_cache_stats();
+        assert_eq!(stats.hits - before.hits, 12);
+        assert_eq!(stats.misses - before.misses, 7);
+
+        // Clearing the cache empties the front caches too.
+        unsafe { super::_clear_cache() };
+        assert_eq!(Ustr::from_existing("hello"), None);
+
+        // A disabled front cache isn't used at all.
+        super::set_front_cache_size(0);
+        let stats = super::front_cache_stats();
+        assert_eq!(u("hello"), "hello");
+        assert_eq!(super::front_cache_stats(), stats);
+    }
+
+    #[test]
+    fn intern_many() {
+        let _t = TEST_LOCK.lock();
+        use super::{Ustr, ustr as u};
+        unsafe { super::_clear_cache() };
+
+        let existing: Vec<Ustr> =
+            (0..100).map(|i| u(&format!("batch{i}"))).collect();
+        // New strings, strings that are already interned and repeats.
+        let words: Vec<String> =
+            (0..10_000).map(|i| format!("batch{}", i % 5_000)).collect();
+        let interned = super::intern_many(words.iter().map(String::as_str));
+        assert_eq!(interned.len(), words.len());
+        assert_eq!(super::num_entries(), 5_000);
+        for (w, us) in words.iter().zip(&interned) {
+            assert_eq!(us, w);
+            assert_eq!(*us, u(w));
+        }
+        assert_eq!(interned[..100], existing[..]);
+
+        let mut out = vec![u("first")];
+        super::extend_into(["batch1", "last"], &mut out);
+        assert_eq!(out, ["first", "batch1", "last"]);
+        assert_eq!(out[1], existing[1]);
+        assert!(super::intern_many([]).is_empty());
+
+        let hash = super::hash::hash(b"prehashed");
+        assert_eq!(Ustr::from_prehashed("prehashed", hash), u("prehashed"));
+        assert_eq!(u("prehashed").precomputed_hash(), hash);
+    }
+
+    #[cfg(feature = "rayon")]
+    #[test]
+    fn par_intern() {
+        let _t = TEST_LOCK.lock();
+        use super::{Ustr, ustr as u};
+        use rayon::prelude::*;
+        unsafe { super::_clear_cache() };
+
+        let existing: Vec<Ustr> =
+            (0..100).map(|i| u(&format!("par{i}"))).collect();
+        let words: Vec<String> =
+            (0..50_000).map(|i| format!("par{}", i % 20_000)).collect();
+        let interned = super::par_intern(words.par_iter().map(String::as_str));
+        assert_eq!(interned.len(), words.len());
+        assert_eq!(super::num_entries(), 20_000);
+        for (w, us) in words.iter().zip(&interned) {
+            assert_eq!(us, w);
+            assert_eq!(*us, u(w));
+        }
+        assert_eq!(interned[..100], existing[..]);
+        assert!(super::par_intern(Vec::<&str>::new()).is_empty());
+
+        let mut scanned: Vec<Ustr> =
+            super::string_cache_iter().into_par_iter().collect();
+        scanned.sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+        let mut expected = interned[..20_000].to_vec();
+        expected.sort_unstable_by(|a, b| a.as_str().cmp(b.as_str()));
+        assert_eq!(scanned, expected);
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn metrics() {
+        use metrics_util::debugging::{DebugValue, DebuggingRecorder};
+        let _t = TEST_LOCK.lock();
+        unsafe { super::_clear_cache() };
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        // Long strings make bins move on to new arenas, unless they're mapped.
+        metrics::with_local_recorder(&recorder, || {
+            for i in 0..50_000 {
+                super::ustr(&format!("{i:0>100}"));
+            }
+            super::publish_metrics();
+        });
+
+        let (mut entries, mut allocated, mut capacity) = (0.0, 0.0, 0.0);
+        let mut rotations = 0;
+        for (key, _, _, value) in snapshotter.snapshot().into_vec() {
+            match (key.key().name(), value) {
+                ("ustr_entries", DebugValue::Gauge(v)) => entries += *v,
+                ("ustr_allocated_bytes", DebugValue::Gauge(v)) => {
+                    allocated += *v
+                }
+                ("ustr_capacity_bytes", DebugValue::Gauge(v)) => capacity += *v,
+                ("ustr_arena_rotations", DebugValue::Counter(n)) => {
+                    rotations += n
+                }
+                _ => {}
+            }
+        }
+        assert_eq!(entries as usize, super::num_entries());
+        assert_eq!(allocated as usize, super::total_allocated());
+        assert_eq!(capacity as usize, super::total_capacity());
+        let arenas: usize =
+            super::stats().bins.iter().map(|bin| bin.arenas - 1).sum();
+        assert_eq!(rotations as usize, arenas);
+    }
+
+    #[cfg(feature = "profiling")]
+    #[test]
+    fn profiling() {
+        use super::{Ustr, cache, intern_many, ustr as u};
+        let _t = TEST_LOCK.lock();
+        unsafe { super::_clear_cache() };
+
+        // Hits in the front cache count like any other call.
+        super::set_front_cache_size(16);
+        for _ in 0..4 {
+            u("hot");
+        }
+        super::set_front_cache_size(0);
+        intern_many(["hot", "warm", "cold", "warm"]);
+        Ustr::from_existing("warm");
+        assert_eq!(Ustr::from_existing("missing"), None);
+
+        let hottest = cache::hottest(usize::MAX);
+        let profile: Vec<_> = hottest
+            .iter()
+            .map(|p| (p.string.as_str(), p.interns, p.lookups))
+            .collect();
+        assert_eq!(profile, [("hot", 5, 0), ("warm", 2, 1), ("cold", 1, 0)]);
+        assert_eq!(cache::coldest(1)[0].string, "cold");
+        assert_eq!(cache::hottest(0), []);
+    }
+
+    #[test]
+    fn tags() {
+        use super::{ArcUstr, Ustr, cache::bytes_by_tag, ustr as u, with_tag};
+        let _t = TEST_LOCK.lock();
+        unsafe { super::_clear_cache() };
+
+        let entry =
+            |len| core::mem::size_of::<super::StringCacheEntry>() + len + 1;
+        u("untagged");
+        with_tag("outer", || {
+            u("abc");
+            // Strings keep the tag they were added under.
+            u("untagged");
+            with_tag("inner", || u("defg"));
+            // Other threads don't see this thread's tag.
+            std::thread::spawn(|| u("elsewhere")).join().unwrap();
+        });
+        Ustr::from_tagged("abc", "inner");
+        assert_eq!(bytes_by_tag(), [("inner", entry(4)), ("outer", entry(3))]);
+
+        // Strings of local interners aren't in the global cache.
+        let local = super::LocalInterner::new();
+        with_tag("local", || local.intern("local string"));
+        assert_eq!(bytes_by_tag().len(), 2);
+
+        // Counted entries have a bigger header. A tag is done with once its
+        // last string is gone.
+        let counted =
+            |len| core::mem::size_of::<super::stringcache::RcEntry>() + len + 1;
+        let arc = with_tag("arc", || ArcUstr::from("temporary"));
+        assert_eq!(bytes_by_tag()[0], ("arc", counted(9)));
+        drop(arc);
+        assert_eq!(bytes_by_tag().len(), 2);
+
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            let words: Vec<String> =
+                (0..100).map(|i| format!("w{i:02}")).collect();
+            with_tag("rayon", || {
+                super::par_intern(words.par_iter().map(String::as_str))
+            });
+            assert_eq!(bytes_by_tag()[0], ("rayon", 100 * entry(3)));
+        }
+    }
+
+    #[cfg(feature = "single-thread")]
+    #[test]
+    fn single_thread() {
+        let _t = TEST_LOCK.lock();
+        use super::{STRING_CACHE, ustr as u};
+        unsafe { super::_clear_cache() };
+
+        assert_eq!(super::num_entries_per_bin().len(), 1);
+
+        // The cache can't be configured with more bins.
+        assert_eq!(
+            super::configure(super::CacheConfig {
+                bins: 64,
+                ..super::CacheConfig::default()
+            }),
+            Err(super::ConfigError::InvalidBins(64))
+        );
+
+        // Any thread can use the cache, one at a time.
+        let hello = u("hello");
+        let other = std::thread::spawn(|| u("hello")).join().unwrap();
+        assert_eq!(other, hello);
+
+        // Taking the lock while another thread holds it panics.
+        let guard = STRING_CACHE.0[0].lock();
+        assert!(std::thread::spawn(|| u("world")).join().is_err());
+        drop(guard);
+        assert_eq!(u("world"), "world");
+    }
+
+    // Inserts from several threads at once.
+    #[cfg(not(feature = "single-thread"))]
+    #[test]
+    fn lock_free_lookups() {
+        let _t = TEST_LOCK.lock();
+        use super::{STRING_CACHE, Ustr, ustr};
+        use std::{sync::mpsc, time::Duration};
+
+        unsafe { super::_clear_cache() };
+
+        let words: Vec<String> = (0..1_000).map(|i| format!("w{i}")).collect();
+        let interned: Vec<Ustr> = words.iter().map(|w| ustr(w)).collect();
+
+        // With every bin locked, hits must still go through.
+        let guards: Vec<_> =
+            STRING_CACHE.0.iter().map(|sc| sc.lock()).collect();
+        let (tx, rx) = mpsc::channel();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for (w, u) in words.iter().zip(&interned) {
+                    assert_eq!(Ustr::from_existing(w), Some(*u));
+                    assert_eq!(ustr(w), *u);
+                }
+                assert_eq!(Ustr::from_existing("not interned"), None);
+                tx.send(()).unwrap();
+            });
+            let res = rx.recv_timeout(Duration::from_secs(10));
+            drop(guards);
+            res.expect("lookup of an existing string blocked on the lock");
+        });
+
+        // Readers keep finding everything while a writer grows the table.
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                for i in 0..50_000 {
+                    ustr(&format!("grow {i}"));
+                }
+            });
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..20 {
+                        for (w, u) in words.iter().zip(&interned) {
+                            assert_eq!(Ustr::from_existing(w), Some(*u));
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(super::num_entries(), 51_000);
+    }
+
+    #[test]
+    fn budget() {
+        let _t = TEST_LOCK.lock();
+        use super::{
+            ArcUstr, Budget, InternError, Ustr, num_entries, set_budget, ustr,
+        };
+
+        unsafe { super::_clear_cache() };
+
+        let u_one = ustr("one");
+        set_budget(Budget {
+            max_entries: Some(2),
+            max_string_len: Some(10),
+            ..Budget::default()
+        });
+        assert_eq!(
+            Ustr::try_from_str("eleven long"),
+            Err(InternError::StringTooLong { len: 11, max: 10 })
+        );
+        let u_two = Ustr::try_from_str("two").unwrap();
+        assert_eq!(
+            Ustr::try_from_str("three"),
+            Err(InternError::TooManyEntries { max: 2 })
+        );
+        assert!(ArcUstr::try_from_str("three").is_err());
+        assert_eq!(num_entries(), 2);
+
+        // Existing strings can still be interned.
+        assert_eq!(Ustr::try_from_str("one"), Ok(u_one));
+        assert_eq!(ustr("two"), u_two);
+
+        // Byte limits count the header and null terminator too.
+        let entry_size = core::mem::size_of::<super::StringCacheEntry>() + 1;
+        let used = 2 * (entry_size + 3);
+        set_budget(Budget {
+            max_bytes: Some(used + entry_size + 5),
+            ..Budget::default()
+        });
+        assert!(Ustr::try_from_str("123456").is_err());
+        assert!(Ustr::try_from_str("12345").is_ok());
+
+        // Dropping counted entries gives their budget back.
+        set_budget(Budget {
+            max_entries: Some(4),
+            ..Budget::default()
+        });
+        let transient = ArcUstr::try_from_str("transient").unwrap();
+        assert!(ArcUstr::try_from_str("other").is_err());
+        drop(transient);
+        assert!(ArcUstr::try_from_str("other").is_ok());
+
+        set_budget(Budget::default());
+        assert!(Ustr::try_from_str("unlimited").is_ok());
+    }
+
+    #[test]
+    fn string_cache_iter_snapshot() {
+        let _t = TEST_LOCK.lock();
+        use super::{string_cache_iter, ustr as u};
+        unsafe { super::_clear_cache() };
+
+        let words: Vec<String> =
+            (0..1000).map(|i| format!("iter{i}")).collect();
+        for w in &words {
+            u(w);
+        }
+
+        let mut iter = string_cache_iter();
+        assert_eq!(iter.len(), words.len());
+        let first = iter.next().unwrap();
+        assert_eq!(iter.len(), words.len() - 1);
+        // Strings added after the snapshot aren't yielded.
+        for i in 0..1000 {
+            u(&format!("late{i}"));
+        }
+        let mut found: Vec<_> = iter.collect();
+        found.push(first);
+        assert_eq!(found.len(), words.len());
+        for s in &found {
+            assert_eq!(s.precomputed_hash(), super::hash::hash(s.as_bytes()));
+            assert_eq!(s.as_cstr().to_bytes(), s.as_bytes());
+        }
+        let mut found: Vec<&str> = found.iter().map(|s| s.as_str()).collect();
+        found.sort_unstable();
+        let mut expected: Vec<&str> =
+            words.iter().map(String::as_str).collect();
+        expected.sort_unstable();
+        assert_eq!(found, expected);
+
+        #[cfg(feature = "rayon")]
+        {
+            use rayon::prelude::*;
+            let mut iter = string_cache_iter();
+            iter.next();
+            assert_eq!(iter.par_iter().count(), 2 * words.len() - 1);
+        }
+    }
+
     #[test]
     fn test_empty_cache() {
+        let _t = TEST_LOCK.lock();
         unsafe { super::_clear_cache() };
         assert_eq!(
             super::string_cache_iter().collect::<Vec<_>>(),
-            Vec::<&'static str>::new()
+            Vec::<super::Ustr>::new()
         );
     }
 
@@ -1200,7 +2155,7 @@ mod tests {
         // Check that we find the right strings
         let mut found_set = HashSet::new();
         for s in found {
-            found_set.insert(s);
+            found_set.insert(s.as_str());
         }
 
         assert!(found_set.contains("hello"));
@@ -1235,40 +2190,3 @@ mod tests {
         assert_eq!(boxed, u);
     }
 }
-
-lazy_static::lazy_static! {
-    static ref STRING_CACHE: Bins = {
-        use std::mem::{self, MaybeUninit};
-        // This deeply unsafe feeling dance allows us to initialize an array of
-        // arbitrary size and will have to tide us over until const generics
-        // land. See:
-        // https://doc.rust-lang.org/beta/std/mem/union.MaybeUninit.html#initializing-an-array-element-by-element
-
-        // Create an uninitialized array of `MaybeUninit`. The `assume_init` is
-        // safe because the type we are claiming to have initialized here is a
-        // bunch of `MaybeUninit`s, which do not require initialization.
-        let mut bins: [MaybeUninit<Mutex<StringCache>>; NUM_BINS] = unsafe {
-            MaybeUninit::uninit().assume_init()
-        };
-
-        // Dropping a `MaybeUninit` does nothing. Thus using raw pointer
-        // assignment instead of `ptr::write` does not cause the old
-        // uninitialized value to be dropped. Also if there is a panic during
-        // this loop, we have a memory leak, but there is no memory safety
-        // issue.
-        for bin in &mut bins[..] {
-            *bin = MaybeUninit::new(Mutex::new(StringCache::default()));
-        }
-
-        // Everything is initialized. Transmute the array to the
-        // initialized type.
-        #[allow(clippy::missing_transmute_annotations)]
-        Bins(unsafe { mem::transmute::<_, [Mutex<StringCache>; NUM_BINS]>(bins) })
-    };
-}
-
-// Use the top bits of the hash to choose a bin
-#[inline]
-fn whichbin(hash: u64) -> usize {
-    ((hash >> TOP_SHIFT as u64) % NUM_BINS as u64) as usize
-}
@@ -0,0 +1,309 @@
+use crate::stringcache::{StringCache, StringCacheEntry};
+use core::{
+    cmp::Ordering,
+    ffi::{CStr, c_char},
+    fmt,
+    hash::{Hash, Hasher},
+    marker::PhantomData,
+    ops::Deref,
+    ptr::NonNull,
+    slice, str,
+};
+
+// Defaults for a `LocalInterner` created with `LocalInterner::new()`. These are
+// a lot smaller than the global cache since local interners are expected to be
+// short-lived.
+const LOCAL_INITIAL_CAPACITY: usize = 1 << 10;
+const LOCAL_INITIAL_ALLOC: usize = 64 << 10;
+
+/// A string interner that owns its memory.
+///
+/// Unlike the global cache behind [`Ustr`](crate::Ustr), strings interned in a
+/// `LocalInterner` are freed when the interner is dropped. The handles it
+/// gives out, [`LocalUstr`], borrow the interner so they can't outlive it, but
+/// otherwise behave like `Ustr`: comparison and hashing are O(1) and the
+/// strings are null-terminated.
+///
+/// `LocalUstr`s are only unique within the interner that created them.
+/// Comparing handles from two different interners gives `false` even if the
+/// strings are equal.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::LocalInterner;
+///
+/// let interner = LocalInterner::new();
+/// let u1 = interner.intern("the quick brown fox");
+/// let u2 = interner.intern("the quick brown fox");
+/// assert_eq!(u1, u2);
+/// assert_eq!(interner.len(), 1);
+/// assert_eq!(u1.as_cstr().to_bytes(), b"the quick brown fox");
+/// ```
+///
+/// # Panics
+///
+/// With the `single-thread` feature, an interner has the same lock as the
+/// bins of the global cache, which panics instead of waiting. Interning into
+/// or looking up strings in the same interner from two threads at once then
+/// panics, even though `LocalInterner` is `Sync`.
+pub struct LocalInterner {
+    cache: StringCache,
+}
+
+impl LocalInterner {
+    /// Create a new, empty interner.
+    pub fn new() -> LocalInterner {
+        LocalInterner::with_capacity(
+            LOCAL_INITIAL_CAPACITY,
+            LOCAL_INITIAL_ALLOC,
+        )
+    }
+
+    /// Create a new, empty interner with room for roughly `capacity / 2`
+    /// strings before its table grows, and `arena_bytes` bytes of string
+    /// storage before it allocates more.
+    pub fn with_capacity(capacity: usize, arena_bytes: usize) -> LocalInterner {
+        LocalInterner {
+            cache: StringCache::new(
+                capacity.max(2).next_power_of_two(),
+                arena_bytes.max(1),
+                2,
+            ),
+        }
+    }
+
+    /// Intern the given string, returning a handle to the interned copy.
+    pub fn intern(&self, string: &str) -> LocalUstr<'_> {
+        let hash = crate::hash::hash(string.as_bytes());
+        // SAFETY: `insert` does not give back a null pointer.
+        unsafe { LocalUstr::from_ptr(self.cache.insert(string, hash)) }
+    }
+
+    /// Get a handle to the given string, but only if it has already been
+    /// interned.
+    pub fn get(&self, string: &str) -> Option<LocalUstr<'_>> {
+        let hash = crate::hash::hash(string.as_bytes());
+        self.cache
+            .get_existing(string, hash)
+            .map(|ptr| unsafe { LocalUstr::from_ptr(ptr) })
+    }
+
+    /// Returns the number of unique strings in the interner.
+    pub fn len(&self) -> usize {
+        self.cache.num_entries()
+    }
+
+    /// Returns true if no strings have been interned.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns the amount of memory allocated and in use by the interner in
+    /// bytes.
+    pub fn total_allocated(&self) -> usize {
+        self.cache.total_allocated()
+    }
+
+    /// Returns the amount of memory reserved by the interner in bytes.
+    pub fn total_capacity(&self) -> usize {
+        self.cache.total_capacity()
+    }
+}
+
+impl Default for LocalInterner {
+    fn default() -> LocalInterner {
+        LocalInterner::new()
+    }
+}
+
+impl Drop for LocalInterner {
+    fn drop(&mut self) {
+        // SAFETY: every `LocalUstr` borrows the interner, so none can still be
+        // alive at this point.
+        unsafe { self.cache.release() }
+    }
+}
+
+impl fmt::Debug for LocalInterner {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LocalInterner")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+/// A handle representing a string in a [`LocalInterner`].
+///
+/// This has the same layout and cost as a [`Ustr`](crate::Ustr), but its
+/// lifetime is bound to the interner that created it.
+#[derive(Copy, Clone)]
+#[repr(transparent)]
+pub struct LocalUstr<'a> {
+    char_ptr: NonNull<u8>,
+    _interner: PhantomData<&'a LocalInterner>,
+}
+
+impl<'a> LocalUstr<'a> {
+    // This is safe as long as `ptr` was returned by a `StringCache` owned by
+    // the `LocalInterner` the handle borrows.
+    unsafe fn from_ptr(ptr: *const u8) -> LocalUstr<'a> {
+        LocalUstr {
+            char_ptr: unsafe { NonNull::new_unchecked(ptr as *mut _) },
+            _interner: PhantomData,
+        }
+    }
+
+    /// Get the interned string as a `str`.
+    pub fn as_str(&self) -> &'a str {
+        // This is safe for the same reasons as `Ustr::as_str()`, and the
+        // memory lives as long as the interner.
+        unsafe {
+            str::from_utf8_unchecked(slice::from_raw_parts(
+                self.char_ptr.as_ptr(),
+                self.len(),
+            ))
+        }
+    }
+
+    /// Get the interned string as a C `char*`.
+    ///
+    /// This includes the null terminator so is safe to pass straight to FFI,
+    /// as long as the interner outlives its use.
+    pub fn as_char_ptr(&self) -> *const c_char {
+        self.char_ptr.as_ptr() as *const c_char
+    }
+
+    /// Get this `LocalUstr` as a [`CStr`].
+    pub fn as_cstr(&self) -> &'a CStr {
+        unsafe {
+            CStr::from_bytes_with_nul_unchecked(slice::from_raw_parts(
+                self.char_ptr.as_ptr(),
+                self.len() + 1,
+            ))
+        }
+    }
+
+    #[inline]
+    fn as_string_cache_entry(&self) -> &'a StringCacheEntry {
+        // The allocator guarantees that the alignment is correct and that
+        // this pointer is non-null
+        unsafe { &*(self.char_ptr.as_ptr().cast::<StringCacheEntry>().sub(1)) }
+    }
+
+    /// Get the length (in bytes) of this string.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.as_string_cache_entry().len
+    }
+
+    /// Returns true if the length is zero.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the precomputed hash for this string.
+    #[inline]
+    pub fn precomputed_hash(&self) -> u64 {
+        self.as_string_cache_entry().hash
+    }
+}
+
+// The strings are immutable and owned by the interner, which is `Sync`, so
+// handles can be shared the same way a `&'a str` can.
+unsafe impl Send for LocalUstr<'_> {}
+unsafe impl Sync for LocalUstr<'_> {}
+
+impl PartialEq for LocalUstr<'_> {
+    fn eq(&self, other: &Self) -> bool {
+        self.char_ptr == other.char_ptr
+    }
+}
+
+impl Eq for LocalUstr<'_> {}
+
+impl PartialEq<str> for LocalUstr<'_> {
+    fn eq(&self, other: &str) -> bool {
+        self.as_str() == other
+    }
+}
+
+impl PartialEq<&str> for LocalUstr<'_> {
+    fn eq(&self, other: &&str) -> bool {
+        self.as_str() == *other
+    }
+}
+
+/// Defer to `str` for ordering, like `Ustr`.
+impl Ord for LocalUstr<'_> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_str().cmp(other.as_str())
+    }
+}
+
+impl PartialOrd for LocalUstr<'_> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+// Just feed the precomputed hash into the Hasher, like `Ustr` does.
+impl Hash for LocalUstr<'_> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.precomputed_hash().hash(state);
+    }
+}
+
+impl Deref for LocalUstr<'_> {
+    type Target = str;
+    fn deref(&self) -> &Self::Target {
+        self.as_str()
+    }
+}
+
+impl AsRef<str> for LocalUstr<'_> {
+    fn as_ref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl fmt::Display for LocalUstr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.as_str())
+    }
+}
+
+impl fmt::Debug for LocalUstr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "l!({:?})", self.as_str())
+    }
+}
+
+#[test]
+fn test_local_interner() {
+    use crate::hash::IdentityHasher;
+
+    let interner = LocalInterner::with_capacity(4, 64);
+    assert!(interner.is_empty());
+    assert_eq!(interner.get("hello"), None);
+
+    // Enough strings to grow both the table and the allocator.
+    let words: Vec<String> = (0..1000).map(|i| format!("word{i}")).collect();
+    let handles: Vec<_> = words.iter().map(|w| interner.intern(w)).collect();
+    assert_eq!(interner.len(), words.len());
+
+    for (w, h) in words.iter().zip(&handles) {
+        assert_eq!(*h, w.as_str());
+        assert_eq!(interner.intern(w), *h);
+        assert_eq!(interner.get(w), Some(*h));
+        assert_eq!(h.as_cstr().to_str().unwrap(), w);
+        assert_eq!(h.precomputed_hash(), crate::hash::hash(w.as_bytes()));
+
+        let mut hasher = IdentityHasher::default();
+        h.hash(&mut hasher);
+        assert_eq!(hasher.finish(), h.precomputed_hash());
+    }
+
+    // The global cache is untouched.
+    assert_eq!(crate::existing_ustr("word999"), None);
+}
@@ -0,0 +1,692 @@
+use crate::{
+    CacheConfig, InternError, STRING_CACHE,
+    bumpalloc::{Backing, LeakyBumpAlloc},
+    cache::{Bins, bin_for},
+    config,
+    stringcache::{EntryRange, StringCache, StringCacheEntry},
+};
+use parking_lot::Mutex;
+use std::{
+    fmt,
+    fs::{File, OpenOptions},
+    io,
+    os::{fd::AsRawFd, unix::fs::FileExt},
+    path::Path,
+    sync::{
+        Arc,
+        atomic::{AtomicU64, Ordering},
+    },
+};
+
+// A persistent cache file holds the entries of the global cache, so that the
+// next process to open it can map them back in instead of interning every
+// string all over again.
+//
+// The file starts with a `FileHeader`, padded to `REGION_ALIGN`, followed by
+// regions that each hold entries of one bin. A region backs one
+// `LeakyBumpAlloc`: it starts with a `RegionHeader` and the entries are bumped
+// down from its end, laid out exactly as in memory. The header records how
+// many bytes at the end of the region are in use, and is only updated once the
+// entries in them have been written, so a process dying in the middle of an
+// insert leaves nothing half-written behind. Regions are appended to the file
+// as the bins fill up; a region whose header never made it to the file is
+// dropped when the file is opened.
+//
+// The tables of a cache hold pointers, which are only valid where the file
+// happens to be mapped, so they aren't kept in the file. Opening the file
+// rebuilds them from the hashes stored in the entries, without copying any
+// string. Each string is hashed once to check its stored hash: an entry whose
+// hash is off would sit in the wrong probe sequence, and interning its string
+// again would add a second copy, breaking pointer equality.
+
+const MAGIC: [u8; 8] = *b"USTRFILE";
+const REGION_MAGIC: [u8; 8] = *b"USTRBIN\0";
+const VERSION: u32 = 1;
+// Regions start and end at multiples of this, so that they can be mapped on
+// systems with pages of up to 64KB.
+const REGION_ALIGN: u64 = 64 << 10;
+// Space taken by the `RegionHeader` at the start of each region, which keeps
+// the entries after it aligned.
+const REGION_HEADER: usize = 64;
+// Hashed to check that the file was written with the same hash function.
+const HASH_PROBE: &[u8] = b"ustr persistent cache";
+
+#[repr(C)]
+#[derive(Default)]
+struct FileHeader {
+    magic: [u8; 8],
+    version: u32,
+    pointer_width: u32,
+    entry_size: u32,
+    entry_align: u32,
+    bins: u32,
+    _reserved: u32,
+    // `hash::hash(HASH_PROBE)`
+    hash_check: u64,
+}
+
+impl FileHeader {
+    fn current(bins: usize) -> FileHeader {
+        FileHeader {
+            magic: MAGIC,
+            version: VERSION,
+            pointer_width: usize::BITS,
+            entry_size: std::mem::size_of::<StringCacheEntry>() as u32,
+            entry_align: std::mem::align_of::<StringCacheEntry>() as u32,
+            bins: bins as u32,
+            _reserved: 0,
+            hash_check: crate::hash::hash(HASH_PROBE),
+        }
+    }
+
+    fn check(&self, bins: usize) -> Result<(), PersistError> {
+        let current = FileHeader::current(bins);
+        if self.magic != MAGIC {
+            Err(PersistError::NotACacheFile)
+        } else if self.version != VERSION {
+            Err(PersistError::UnsupportedVersion(self.version))
+        } else if self.pointer_width != current.pointer_width
+            || self.entry_size != current.entry_size
+            || self.entry_align != current.entry_align
+        {
+            Err(PersistError::LayoutMismatch)
+        } else if self.hash_check != current.hash_check {
+            Err(PersistError::HashMismatch)
+        } else if self.bins != current.bins {
+            Err(PersistError::BinsMismatch {
+                file: self.bins as usize,
+                config: bins,
+            })
+        } else {
+            Ok(())
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct RegionHeader {
+    magic: [u8; 8],
+    bin: u64,
+    // Size of the region including this header.
+    size: u64,
+    // Bytes in use at the end of the region.
+    used: AtomicU64,
+}
+
+// The bytes of a header, as read from or written to the file.
+fn bytes_of<T>(header: &mut T) -> &mut [u8] {
+    // Headers are made of plain integers, so any bytes are a valid header.
+    unsafe {
+        std::slice::from_raw_parts_mut(
+            header as *mut T as *mut u8,
+            std::mem::size_of::<T>(),
+        )
+    }
+}
+
+/// A persistent cache file opened by [`open_persistent()`].
+#[derive(Debug)]
+pub(crate) struct PersistentFile {
+    file: File,
+    // Length of the file, where the next region goes.
+    len: Mutex<u64>,
+}
+
+// Map `size` bytes of `file` from `offset`.
+fn map(file: &File, offset: u64, size: u64) -> io::Result<*mut u8> {
+    // SAFETY: asking for a fresh shared mapping of the file has no
+    // preconditions.
+    let ptr = unsafe {
+        libc::mmap(
+            std::ptr::null_mut(),
+            size as usize,
+            libc::PROT_READ | libc::PROT_WRITE,
+            libc::MAP_SHARED,
+            file.as_raw_fd(),
+            offset as libc::off_t,
+        )
+    };
+    if ptr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(ptr as *mut u8)
+}
+
+// The allocator for a region mapped at `base`.
+fn region_alloc(
+    base: *mut u8,
+    size: u64,
+    used: u64,
+    alignment: usize,
+    backing: Backing,
+) -> Option<LeakyBumpAlloc> {
+    // SAFETY: the region is mapped for `size` bytes, and `used` was checked to
+    // fit after the header.
+    unsafe {
+        let end = base.add(size as usize);
+        LeakyBumpAlloc::from_raw_parts(
+            base.add(REGION_HEADER),
+            end,
+            end.sub(used as usize),
+            alignment,
+            backing,
+        )
+    }
+}
+
+// Append a region with room for `capacity` bytes of entries of `bin` to the
+// file, and map it.
+pub(crate) fn new_region(
+    file: Arc<PersistentFile>,
+    bin: usize,
+    capacity: usize,
+    alignment: usize,
+) -> Option<LeakyBumpAlloc> {
+    let size = (capacity as u64)
+        .checked_add(REGION_HEADER as u64)?
+        .checked_next_multiple_of(REGION_ALIGN)?;
+    let mut len = file.len.lock();
+    let offset = *len;
+    file.file.set_len(offset.checked_add(size)?).ok()?;
+    let Ok(base) = map(&file.file, offset, size) else {
+        let _ = file.file.set_len(offset);
+        return None;
+    };
+    *len = offset + size;
+    drop(len);
+
+    // The magic goes in last, so that a region isn't taken for valid until
+    // the rest of its header is written.
+    unsafe {
+        let header = base as *mut RegionHeader;
+        (*header).bin = bin as u64;
+        (*header).size = size;
+        (*header).used.store(0, Ordering::Relaxed);
+        std::sync::atomic::fence(Ordering::Release);
+        (*header).magic = REGION_MAGIC;
+    }
+    region_alloc(base, size, 0, alignment, Backing::File { file, bin })
+}
+
+// Record that `used` bytes at the end of the region whose entries start at
+// `start` are in use.
+pub(crate) fn publish(start: *mut u8, used: usize) {
+    // SAFETY: every file-backed allocator starts right after its header.
+    unsafe {
+        let header = start.sub(REGION_HEADER) as *const RegionHeader;
+        (*header).used.store(used as u64, Ordering::Release);
+    }
+}
+
+// Unmap the region whose entries start at `start`.
+//
+// This is safe as long as nothing in the region is used afterwards.
+pub(crate) unsafe fn unmap(start: *mut u8, capacity: usize) {
+    unsafe {
+        libc::munmap(
+            start.sub(REGION_HEADER) as *mut libc::c_void,
+            capacity + REGION_HEADER,
+        );
+    }
+}
+
+/// Error returned by [`open_persistent()`].
+#[derive(Debug)]
+pub enum PersistError {
+    /// The global cache was already created, so it can no longer be loaded
+    /// from a file.
+    AlreadyInitialized,
+    /// The file couldn't be opened, read, locked or mapped. It is locked
+    /// while a process has it open.
+    Io(io::Error),
+    /// The file isn't empty and doesn't start like a cache file.
+    NotACacheFile,
+    /// The file was written in another version of the format.
+    UnsupportedVersion(u32),
+    /// The file was written on a platform with another pointer width or
+    /// entry layout.
+    LayoutMismatch,
+    /// The file was written with another hash function.
+    HashMismatch,
+    /// The file was written by a cache with another number of bins than
+    /// configured with [`configure()`](crate::configure).
+    BinsMismatch {
+        /// Number of bins in the file.
+        file: usize,
+        /// Number of bins configured.
+        config: usize,
+    },
+    /// The contents of the file are invalid.
+    Corrupt,
+    /// The strings in the file don't fit in the cache's
+    /// [`Budget`](crate::Budget).
+    Intern(InternError),
+}
+
+impl fmt::Display for PersistError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PersistError::AlreadyInitialized => {
+                write!(f, "the string cache has already been initialized")
+            }
+            PersistError::Io(e) => write!(f, "cache file error: {e}"),
+            PersistError::NotACacheFile => {
+                write!(f, "file is not a string cache file")
+            }
+            PersistError::UnsupportedVersion(version) => {
+                write!(f, "unsupported cache file version {version}")
+            }
+            PersistError::LayoutMismatch => {
+                write!(f, "cache file was written on another platform")
+            }
+            PersistError::HashMismatch => {
+                write!(f, "cache file was written with another hash function")
+            }
+            PersistError::BinsMismatch { file, config } => write!(
+                f,
+                "cache file has {file} bins but {config} are configured"
+            ),
+            PersistError::Corrupt => write!(f, "cache file is corrupt"),
+            PersistError::Intern(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for PersistError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            PersistError::Io(e) => Some(e),
+            PersistError::Intern(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PersistError {
+    fn from(e: io::Error) -> PersistError {
+        PersistError::Io(e)
+    }
+}
+
+impl From<InternError> for PersistError {
+    fn from(e: InternError) -> PersistError {
+        PersistError::Intern(e)
+    }
+}
+
+// Check that the entries of a region of `bin` out of `bins` hold valid
+// strings with the right hash, so they can be handed out as `Ustr`s. Returns
+// the number of entries.
+fn check_entries(
+    alloc: &LeakyBumpAlloc,
+    bin: usize,
+    bins: usize,
+) -> Result<usize, PersistError> {
+    let (start, end) = alloc.allocated_range();
+    let mut count = 0;
+    for entry in unsafe { EntryRange::new((start, end)) } {
+        // SAFETY: `EntryRange` only yields entries whose header is in the
+        // region, and the string is only read once it's known to fit.
+        unsafe {
+            let header = std::mem::size_of::<StringCacheEntry>();
+            let chars = (entry as *const u8).add(header);
+            let len = (*entry).len;
+            if len >= end as usize - chars as usize
+                || *chars.add(len) != 0
+                || bin_for((*entry).hash, bins) != bin
+            {
+                return Err(PersistError::Corrupt);
+            }
+            let bytes = std::slice::from_raw_parts(chars, len);
+            if std::str::from_utf8(bytes).is_err()
+                || crate::hash::hash(bytes) != (*entry).hash
+            {
+                return Err(PersistError::Corrupt);
+            }
+        }
+        count += 1;
+    }
+    Ok(count)
+}
+
+// Open the cache file at `path`, creating it if it doesn't exist, and load it
+// into new bins laid out as given by `config`. Returns the bins and the number
+// of strings loaded.
+pub(crate) fn open(
+    path: &Path,
+    config: &CacheConfig,
+) -> Result<(Bins, usize), PersistError> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    // Another process appending to the file would overwrite our regions.
+    // SAFETY: `flock` has no preconditions.
+    if unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) }
+        != 0
+    {
+        return Err(io::Error::last_os_error().into());
+    }
+
+    let mut len = file.metadata()?.len();
+    if len == 0 {
+        file.write_all_at(bytes_of(&mut FileHeader::current(config.bins)), 0)?;
+        file.set_len(REGION_ALIGN)?;
+        len = REGION_ALIGN;
+    } else {
+        let mut header = FileHeader::default();
+        file.read_exact_at(bytes_of(&mut header), 0)
+            .map_err(|_| PersistError::NotACacheFile)?;
+        header.check(config.bins)?;
+    }
+
+    // Find the regions of each bin.
+    let alignment = std::mem::align_of::<StringCacheEntry>();
+    let mut regions: Vec<Vec<(u64, u64, u64)>> = vec![Vec::new(); config.bins];
+    let mut offset = REGION_ALIGN;
+    while offset + REGION_HEADER as u64 <= len {
+        let mut header = RegionHeader::default();
+        file.read_exact_at(bytes_of(&mut header), offset)?;
+        let size = header.size;
+        // A region is appended by growing the file before its header is
+        // written, so the header of the last one may be missing.
+        if header.magic != REGION_MAGIC
+            || size == 0
+            || size % REGION_ALIGN != 0
+            || size > len - offset
+        {
+            break;
+        }
+        let used = header.used.into_inner();
+        let bin = header.bin as usize;
+        if bin >= config.bins
+            || used > size - REGION_HEADER as u64
+            || used % alignment as u64 != 0
+        {
+            return Err(PersistError::Corrupt);
+        }
+        regions[bin].push((offset, size, used));
+        offset += size;
+    }
+    if offset < len {
+        file.set_len(offset)?;
+        len = offset;
+    }
+
+    let file = Arc::new(PersistentFile {
+        file,
+        len: Mutex::new(len),
+    });
+    // Every region mapped so far, as the start of its entries and their
+    // capacity. Nothing has been handed out from them until the bins are
+    // returned, so they can all be unmapped again if loading fails.
+    let mut mapped = Vec::new();
+    let res = load(&file, regions, config, &mut mapped);
+    if res.is_err() {
+        for (start, capacity) in mapped {
+            // SAFETY: see above.
+            unsafe { unmap(start, capacity) };
+        }
+    }
+    res
+}
+
+// Map the regions found by `open()` and load them into new bins, recording
+// every region mapped in `mapped`. If that fails, the bins loaded so far give
+// back the budget their entries took.
+fn load(
+    file: &Arc<PersistentFile>,
+    regions: Vec<Vec<(u64, u64, u64)>>,
+    config: &CacheConfig,
+    mapped: &mut Vec<(*mut u8, usize)>,
+) -> Result<(Bins, usize), PersistError> {
+    let mut bins = Vec::with_capacity(config.bins);
+    let mut loaded = 0;
+    for (bin, regions) in regions.into_iter().enumerate() {
+        match load_bin(file, bin, regions, config, mapped) {
+            Ok((sc, adopted)) => {
+                bins.push(sc);
+                loaded += adopted;
+            }
+            Err(e) => {
+                for sc in &mut bins {
+                    sc.release_all_budget();
+                }
+                return Err(e);
+            }
+        }
+    }
+    Ok((Bins(bins.into_boxed_slice()), loaded))
+}
+
+// Load the regions of `bin` into a new bin, and return it with the number of
+// entries loaded.
+fn load_bin(
+    file: &Arc<PersistentFile>,
+    bin: usize,
+    regions: Vec<(u64, u64, u64)>,
+    config: &CacheConfig,
+    mapped: &mut Vec<(*mut u8, usize)>,
+) -> Result<(StringCache, usize), PersistError> {
+    let alignment = std::mem::align_of::<StringCacheEntry>();
+    let backing = Backing::File {
+        file: file.clone(),
+        bin,
+    };
+    let mut allocs = Vec::with_capacity(regions.len().max(1));
+    let mut entries = 0;
+    for (offset, size, used) in regions {
+        let base = map(&file.file, offset, size)?;
+        mapped.push((
+            base.wrapping_add(REGION_HEADER),
+            size as usize - REGION_HEADER,
+        ));
+        let alloc = region_alloc(base, size, used, alignment, backing.clone())
+            .ok_or(PersistError::Corrupt)?;
+        entries += check_entries(&alloc, bin, config.bins)?;
+        allocs.push(alloc);
+    }
+    if allocs.is_empty() {
+        let alloc = LeakyBumpAlloc::try_new(
+            config.bin_arena_bytes(),
+            alignment,
+            backing,
+        )
+        .ok_or(InternError::OutOfMemory)?;
+        let (_, end) = alloc.allocated_range();
+        mapped.push((
+            end.wrapping_sub(alloc.capacity()) as *mut u8,
+            alloc.capacity(),
+        ));
+        allocs.push(alloc);
+    }
+    // Large strings are stored in the file like any other.
+    let mut sc = StringCache::new(
+        config.bin_table_capacity(),
+        config.bin_arena_bytes(),
+        config.growth_factor,
+    )
+    .budgeted()
+    .large_string_threshold(usize::MAX)
+    .frozen_policy(config.frozen_policy);
+    // SAFETY: the entries were checked above.
+    let adopted = match unsafe { sc.adopt_allocs(allocs) } {
+        Ok(adopted) if adopted == entries => adopted,
+        res => {
+            sc.release_all_budget();
+            // A string that's in the file twice would be left in the arena
+            // without being in the table, and iterating the cache would
+            // yield it anyway. The cache never writes one.
+            return Err(res.map_or_else(Into::into, |_| PersistError::Corrupt));
+        }
+    };
+    let sc = sc.record_tags();
+    #[cfg(feature = "metrics")]
+    let sc = sc.metrics_bin(bin);
+    Ok((sc, adopted))
+}
+
+// Bins loaded by `open_persistent()`, which the global cache is created from.
+static OPENED: Mutex<Option<Bins>> = Mutex::new(None);
+
+pub(crate) fn take_opened() -> Option<Bins> {
+    OPENED.lock().take()
+}
+
+/// Back the global string cache with the cache file at `path`, creating the
+/// file if it doesn't exist, and return the number of strings loaded from it.
+///
+/// Every string interned afterwards is stored in the file, and the strings
+/// already in it are available right away without being copied: the file is
+/// mapped into memory and `Ustr`s point straight into it. Only the cache's
+/// tables, which hold pointers, are rebuilt from the hashes stored with the
+/// strings, after checking each of them against its string. This pays off for
+/// programs that intern a large, mostly stable vocabulary at every start.
+///
+/// Like [`configure()`](crate::configure), this must be called before the
+/// first `Ustr` is created, and the cache is laid out as configured. The file
+/// records the version of its format, the pointer width, the hash function and
+/// the number of bins, and is rejected if any of them doesn't match. It is
+/// locked for as long as the process runs, so only one process can use it at
+/// a time.
+///
+/// Strings are written to the file as they are interned, and the operating
+/// system writes them back to disk even if the process crashes. The strings
+/// of [`ArcUstr`](crate::ArcUstr)s aren't stored in the file.
+///
+/// # Examples
+///
+/// ```no_run
+/// use ustr::ustr;
+///
+/// let loaded = ustr::open_persistent("/var/cache/myapp/symbols.ustr").unwrap();
+/// println!("{loaded} symbols loaded");
+/// let _ = ustr("symbol");
+/// ```
+pub fn open_persistent(path: impl AsRef<Path>) -> Result<usize, PersistError> {
+    let loaded = config::init_with(|config| {
+        let (bins, loaded) = open(path.as_ref(), config)?;
+        *OPENED.lock() = Some(bins);
+        Ok(loaded)
+    })
+    .unwrap_or(Err(PersistError::AlreadyInitialized))?;
+    crate::sync::Lazy::force(&STRING_CACHE);
+    Ok(loaded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_reopen() {
+        let path = std::env::temp_dir()
+            .join(format!("ustr-persist-{}.ustr", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let config = CacheConfig {
+            bins: 4,
+            initial_arena_bytes: 4 << 10,
+            ..CacheConfig::default()
+        };
+        let hash = |s: &str| crate::hash::hash(s.as_bytes());
+        let insert = |bins: &Bins, s: &str| {
+            bins.0[bins.whichbin(hash(s))].insert(s, hash(s))
+        };
+        let words: Vec<String> =
+            (0..20_000).map(|i| format!("persist{i}")).collect();
+        let big = "b".repeat(100 << 10);
+
+        let (mut bins, loaded) = open(&path, &config).unwrap();
+        assert_eq!(loaded, 0);
+        for w in &words {
+            insert(&bins, w);
+        }
+        insert(&bins, &big);
+        // The file is locked while it's open.
+        assert!(matches!(open(&path, &config), Err(PersistError::Io(_))));
+        for sc in bins.0.iter_mut() {
+            unsafe { sc.release() };
+        }
+        drop(bins);
+
+        let (mut bins, loaded) = open(&path, &config).unwrap();
+        assert_eq!(loaded, words.len() + 1);
+        for w in words.iter().chain([&big]) {
+            let sc = &bins.0[bins.whichbin(hash(w))];
+            assert!(sc.get_existing(w, hash(w)).is_some());
+        }
+        let new = insert(&bins, "new");
+        assert_eq!(insert(&bins, "new"), new);
+        assert_eq!(insert(&bins, &words[0]), {
+            let sc = &bins.0[bins.whichbin(hash(&words[0]))];
+            sc.get_existing(&words[0], hash(&words[0])).unwrap()
+        });
+        for sc in bins.0.iter_mut() {
+            unsafe { sc.release() };
+        }
+        drop(bins);
+
+        // A region that was being appended when the process died is dropped.
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len + REGION_ALIGN).unwrap();
+        drop(file);
+        let (mut bins, loaded) = open(&path, &config).unwrap();
+        assert_eq!(loaded, words.len() + 2);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), len);
+        for sc in bins.0.iter_mut() {
+            unsafe { sc.release() };
+        }
+        drop(bins);
+
+        let other = CacheConfig {
+            bins: 8,
+            ..config.clone()
+        };
+        assert!(matches!(
+            open(&path, &other),
+            Err(PersistError::BinsMismatch { file: 4, config: 8 })
+        ));
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.write_all_at(b"NOTUSTR!", 0).unwrap();
+        drop(file);
+        assert!(matches!(
+            open(&path, &config),
+            Err(PersistError::NotACacheFile)
+        ));
+        std::fs::remove_file(&path).unwrap();
+
+        // Rewrite a string in a file, keeping its stored hash.
+        let config = CacheConfig { bins: 1, ..config };
+        let (mut bins, _) = open(&path, &config).unwrap();
+        insert(&bins, "first");
+        insert(&bins, "other");
+        for sc in bins.0.iter_mut() {
+            unsafe { sc.release() };
+        }
+        drop(bins);
+        let mut contents = std::fs::read(&path).unwrap();
+        let pos = contents.windows(6).position(|w| w == b"other\0").unwrap();
+        contents[pos..pos + 5].copy_from_slice(b"first");
+        std::fs::write(&path, &contents).unwrap();
+        // The string no longer matches its hash, so the file is rejected, and
+        // the regions that were mapped are unmapped again.
+        assert!(matches!(open(&path, &config), Err(PersistError::Corrupt)));
+        #[cfg(target_os = "linux")]
+        {
+            let maps = std::fs::read_to_string("/proc/self/maps").unwrap();
+            assert!(!maps.contains(path.to_str().unwrap()));
+        }
+        // With the hash fixed up, the file holds the same string twice, which
+        // is rejected as well.
+        let header = pos - std::mem::size_of::<StringCacheEntry>();
+        contents[header..header + 8]
+            .copy_from_slice(&hash("first").to_ne_bytes());
+        std::fs::write(&path, contents).unwrap();
+        assert!(matches!(open(&path, &config), Err(PersistError::Corrupt)));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,168 @@
+// Per-string counters of intern calls and lookups, kept in each entry's
+// header with the `profiling` feature. Every way of getting a `Ustr` from the
+// global cache passes its result through `interned()` or `looked_up()`, which
+// do nothing without the feature.
+
+use crate::Ustr;
+#[cfg(feature = "profiling")]
+use crate::{StringCacheIterator, string_cache_iter};
+#[cfg(feature = "profiling")]
+use alloc::vec::Vec;
+#[cfg(feature = "profiling")]
+use core::sync::atomic::Ordering;
+
+// Count a call that interned `u`.
+#[cfg(feature = "profiling")]
+#[inline]
+pub(crate) fn interned(u: Ustr) -> Ustr {
+    u.as_string_cache_entry()
+        .interns
+        .fetch_add(1, Ordering::Relaxed);
+    u
+}
+
+// Count a call that looked up `u` without interning it.
+#[cfg(feature = "profiling")]
+#[inline]
+pub(crate) fn looked_up(u: Ustr) -> Ustr {
+    u.as_string_cache_entry()
+        .lookups
+        .fetch_add(1, Ordering::Relaxed);
+    u
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline]
+pub(crate) fn interned(u: Ustr) -> Ustr {
+    u
+}
+
+#[cfg(not(feature = "profiling"))]
+#[inline]
+pub(crate) fn looked_up(u: Ustr) -> Ustr {
+    u
+}
+
+/// How often a string of the global cache has been used, as reported by
+/// [`hottest()`] and [`coldest()`].
+///
+/// Only calls that give a [`Ustr`] are counted, not those that give an
+/// [`ArcUstr`](crate::ArcUstr).
+#[cfg(feature = "profiling")]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StringProfile {
+    /// The string.
+    pub string: Ustr,
+    /// Number of times it was interned, with [`Ustr::from`],
+    /// [`ustr()`](crate::ustr()), [`intern_many()`](crate::intern_many) and
+    /// the like, including the first time.
+    pub interns: u64,
+    /// Number of times it was looked up with [`Ustr::from_existing`] or
+    /// [`existing_ustr()`](crate::existing_ustr).
+    pub lookups: u64,
+}
+
+#[cfg(feature = "profiling")]
+impl StringProfile {
+    /// Intern calls and lookups together.
+    pub fn uses(&self) -> u64 {
+        self.interns + self.lookups
+    }
+
+    fn of(string: Ustr) -> StringProfile {
+        let entry = string.as_string_cache_entry();
+        StringProfile {
+            string,
+            interns: entry.interns.load(Ordering::Relaxed),
+            lookups: entry.lookups.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// Returns the `n` most used strings of the global cache, most used first.
+///
+/// These are the strings that dominate traffic, and the candidates for being
+/// interned once at startup. Strings with the same number of uses are ordered
+/// by their contents. Only strings that a [`Ustr`] was handed out for are
+/// counted, not those of live [`ArcUstr`](crate::ArcUstr)s.
+///
+/// This walks the whole cache, so it's meant for reports rather than hot
+/// paths.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{Ustr, ustr};
+/// # unsafe { ustr::_clear_cache() };
+///
+/// for _ in 0..3 {
+///     ustr("GET");
+/// }
+/// ustr("POST");
+/// Ustr::from_existing("POST");
+/// ustr("DELETE");
+///
+/// let hottest = ustr::cache::hottest(2);
+/// assert_eq!(hottest[0].string, "GET");
+/// assert_eq!(hottest[0].interns, 3);
+/// assert_eq!(hottest[1].string, "POST");
+/// assert_eq!(hottest[1].uses(), 2);
+/// ```
+#[cfg(feature = "profiling")]
+pub fn hottest(n: usize) -> Vec<StringProfile> {
+    ranked(string_cache_iter(), n, |a, b| {
+        b.uses()
+            .cmp(&a.uses())
+            .then_with(|| a.string.cmp(&b.string))
+    })
+}
+
+/// Returns the `n` least used strings of the global cache, least used first.
+///
+/// Strings that were interned once and never used again come first: the
+/// memory spent interning them was wasted. Strings with the same number of
+/// uses are ordered by their contents.
+///
+/// This walks the whole cache, so it's meant for reports rather than hot
+/// paths.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::ustr;
+/// # unsafe { ustr::_clear_cache() };
+///
+/// ustr("common");
+/// ustr("common");
+/// ustr("once");
+///
+/// let coldest = ustr::cache::coldest(1);
+/// assert_eq!(coldest[0].string, "once");
+/// assert_eq!(coldest[0].uses(), 1);
+/// ```
+#[cfg(feature = "profiling")]
+pub fn coldest(n: usize) -> Vec<StringProfile> {
+    ranked(string_cache_iter(), n, |a, b| {
+        a.uses()
+            .cmp(&b.uses())
+            .then_with(|| a.string.cmp(&b.string))
+    })
+}
+
+// The first `n` profiles of the strings in `iter` in the given order, without
+// sorting all of them.
+#[cfg(feature = "profiling")]
+fn ranked(
+    iter: StringCacheIterator,
+    n: usize,
+    order: impl Fn(&StringProfile, &StringProfile) -> core::cmp::Ordering,
+) -> Vec<StringProfile> {
+    let mut profiles: Vec<StringProfile> =
+        iter.map(StringProfile::of).collect();
+    if n < profiles.len() {
+        profiles.select_nth_unstable_by(n, &order);
+        profiles.truncate(n);
+    }
+    profiles.sort_unstable_by(order);
+    profiles
+}
@@ -1,6 +1,6 @@
 use super::*;
 use serde::{
-    de::{Deserialize, Deserializer, Error, SeqAccess, Visitor},
+    de::{Deserialize, Deserializer, Error, SeqAccess, Unexpected, Visitor},
     ser::{Serialize, SerializeSeq, Serializer},
 };
 
@@ -9,10 +9,10 @@ impl Serialize for Bins {
     where
         S: Serializer,
     {
-        let strings: Vec<&'static str> = string_cache_iter().collect();
+        let strings = string_cache_iter();
         let mut seq = serializer.serialize_seq(Some(strings.len()))?;
         for s in strings {
-            match seq.serialize_element(s) {
+            match seq.serialize_element(s.as_str()) {
                 Ok(_) => (),
                 Err(e) => {
                     panic!("Error serializing \"{}\": {}", s, e);
@@ -102,3 +102,151 @@ impl Serialize for Ustr {
         serializer.serialize_str(self.as_str())
     }
 }
+
+/// A [`Ustr`] that only deserializes from strings that are already in the
+/// cache.
+///
+/// Deserializing a plain `Ustr` interns whatever string it is given, so
+/// untrusted input can grow the cache without bound. `ExistingUstr` resolves
+/// through [`Ustr::from_existing`] instead and rejects unknown strings with an
+/// error naming the value. This makes it a good fit for enum-like values that
+/// are checked against a vocabulary interned up front, including as the key
+/// of a map or set.
+///
+/// To use it on a plain `Ustr` field, see [`existing`].
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{ExistingUstr, ustr};
+///
+/// let red = ustr("red");
+/// let color: ExistingUstr = serde_json::from_str("\"red\"").unwrap();
+/// assert_eq!(color, red);
+///
+/// let err = serde_json::from_str::<ExistingUstr>("\"mauve\"").unwrap_err();
+/// assert!(err.to_string().contains("\"mauve\""));
+/// ```
+#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[repr(transparent)]
+pub struct ExistingUstr(pub Ustr);
+
+impl Deref for ExistingUstr {
+    type Target = Ustr;
+    fn deref(&self) -> &Ustr {
+        &self.0
+    }
+}
+
+impl From<Ustr> for ExistingUstr {
+    fn from(u: Ustr) -> ExistingUstr {
+        ExistingUstr(u)
+    }
+}
+
+impl From<ExistingUstr> for Ustr {
+    fn from(u: ExistingUstr) -> Ustr {
+        u.0
+    }
+}
+
+impl PartialEq<Ustr> for ExistingUstr {
+    fn eq(&self, other: &Ustr) -> bool {
+        self.0 == *other
+    }
+}
+
+impl PartialEq<ExistingUstr> for Ustr {
+    fn eq(&self, other: &ExistingUstr) -> bool {
+        *self == other.0
+    }
+}
+
+impl fmt::Display for ExistingUstr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+pub struct ExistingUstrVisitor {}
+impl ExistingUstrVisitor {
+    #[allow(clippy::new_without_default)]
+    pub fn new() -> Self {
+        ExistingUstrVisitor {}
+    }
+}
+
+impl<'de> Visitor<'de> for ExistingUstrVisitor {
+    type Value = ExistingUstr;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("an already interned string")
+    }
+
+    fn visit_str<E>(self, s: &str) -> Result<Self::Value, E>
+    where
+        E: Error,
+    {
+        Ustr::from_existing(s)
+            .map(ExistingUstr)
+            .ok_or_else(|| E::invalid_value(Unexpected::Str(s), &self))
+    }
+}
+
+impl<'de> Deserialize<'de> for ExistingUstr {
+    fn deserialize<D>(deserializer: D) -> Result<ExistingUstr, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        deserializer.deserialize_str(ExistingUstrVisitor::new())
+    }
+}
+
+impl Serialize for ExistingUstr {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+/// Serde helpers to deserialize a `Ustr` field only from strings that are
+/// already in the cache, see [`ExistingUstr`].
+///
+/// # Examples
+///
+/// ```
+/// use serde::Deserialize;
+/// use ustr::{Ustr, ustr};
+///
+/// #[derive(Deserialize)]
+/// struct Pixel {
+///     #[serde(with = "ustr::serialization::existing")]
+///     color: Ustr,
+/// }
+///
+/// ustr("red");
+/// let p: Pixel = serde_json::from_str(r#"{"color": "red"}"#).unwrap();
+/// assert_eq!(p.color, "red");
+/// assert!(serde_json::from_str::<Pixel>(r#"{"color": "mauve"}"#).is_err());
+/// ```
+pub mod existing {
+    use super::*;
+
+    /// Serialize a `Ustr` as a plain string.
+    pub fn serialize<S>(u: &Ustr, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(u.as_str())
+    }
+
+    /// Deserialize a `Ustr`, failing if the string is not in the cache.
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Ustr, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        ExistingUstr::deserialize(deserializer).map(|u| u.0)
+    }
+}
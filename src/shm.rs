@@ -0,0 +1,743 @@
+use crate::{InternError, Ustr, stringcache::StringCacheEntry};
+use std::{
+    cell::UnsafeCell,
+    ffi::CString,
+    fmt, io,
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+// A `SharedCache` lives in a POSIX shared-memory segment that any number of
+// processes map, wherever it lands in their address space. Nothing in the
+// segment is a pointer: tables and handles hold offsets from its start.
+//
+// The segment starts with a `Header`, which holds a process-shared, robust
+// lock for each bin, followed by memory that is bumped upwards by every
+// allocation: tables and entries alike. Entries have the same layout as in
+// the global cache, so a `Ustr` can point straight at one. Nothing is ever
+// freed, so a table that was outgrown stays where it is, and readers that are
+// still probing it find everything it had.
+//
+// Each bin's table is an open-addressing table of entry offsets, 0 meaning
+// empty, probed linearly. It's published along with its size in a single
+// word, so a process dying while growing it can't leave the two out of step.
+// Lookups don't take the lock: slots are only ever filled after their entry
+// has been written, and a lookup that misses takes the lock and looks again
+// before inserting. If a process dies holding a bin's lock, the next process
+// to take it counts the entries again, since the count is the only thing an
+// interrupted insert can leave inconsistent.
+
+const MAGIC: [u8; 8] = *b"USTRSHM\0";
+const VERSION: u32 = 2;
+const BINS: usize = 64;
+// Initial number of slots of each bin's table.
+const INITIAL_SLOTS: usize = 1024;
+// Tables are aligned to this, which leaves room for their size in the low
+// bits of their offset.
+const TABLE_ALIGN: u64 = 64;
+// Hashed to check that every process uses the same hash function.
+const HASH_PROBE: &[u8] = b"ustr shared cache";
+// How long `open()` waits for another process to set up the segment.
+const SETUP_TIMEOUT: Duration = Duration::from_secs(10);
+
+const SETTING_UP: u32 = 0;
+const READY: u32 = 1;
+
+#[repr(C)]
+struct Header {
+    magic: [u8; 8],
+    version: u32,
+    state: AtomicU32,
+    size: u64,
+    mutex_size: u32,
+    pointer_width: u32,
+    // Size of an entry's header, which depends on the `profiling` feature.
+    entry_size: u32,
+    // `hash::hash(HASH_PROBE)`
+    hash_check: u64,
+    // Offset of the first free byte.
+    top: AtomicU64,
+    bins: [Bin; BINS],
+}
+
+#[repr(C, align(64))]
+struct Bin {
+    lock: UnsafeCell<libc::pthread_mutex_t>,
+    // Offset of the table, with the log2 of its number of slots in the low
+    // bits.
+    table: AtomicU64,
+    // Number of entries, only touched with the lock held.
+    len: AtomicU64,
+}
+
+/// A string cache shared by the processes on a machine, kept in a named POSIX
+/// shared-memory segment.
+///
+/// Every process that opens the same segment gets the same entries for the
+/// same strings: the same bytes and the same precomputed hash. Each entry has
+/// a [`handle()`](SharedCache::handle), an offset into the segment, that can
+/// be sent to another process and turned back into a `Ustr` there with
+/// [`from_handle()`](SharedCache::from_handle). This lets cooperating
+/// processes hold one copy of a large symbol set between them.
+///
+/// The `Ustr`s handed out point into the segment, which stays mapped until the
+/// process exits. They compare equal to each other like any `Ustr`, but not to
+/// `Ustr`s for the same string from the global cache or another
+/// `SharedCache`.
+///
+/// The segment has a fixed size, given by the process that creates it.
+/// Strings are never removed, and the segment lives on after every process
+/// has exited, until [`SharedCache::unlink()`] is called.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::SharedCache;
+///
+/// let name = format!("/ustr-doc-{}", std::process::id());
+/// let cache = SharedCache::open(&name, 16 << 20).unwrap();
+/// let hello = cache.intern("hello");
+/// assert_eq!(hello, "hello");
+///
+/// // Handles can be sent to another process that opened the same segment.
+/// let handle = cache.handle(hello).unwrap();
+/// assert_eq!(cache.from_handle(handle), Some(hello));
+/// # SharedCache::unlink(&name).unwrap();
+/// ```
+pub struct SharedCache {
+    base: NonNull<u8>,
+    size: usize,
+}
+
+// The segment is only changed through atomics and with the bins' locks held.
+unsafe impl Send for SharedCache {}
+unsafe impl Sync for SharedCache {}
+
+/// Error returned by [`SharedCache::open()`].
+#[derive(Debug)]
+pub enum SharedError {
+    /// The segment couldn't be created, opened or mapped.
+    Io(io::Error),
+    /// The name contains a nul byte.
+    InvalidName,
+    /// The requested size is too small to hold the cache's tables.
+    TooSmall,
+    /// The segment was set up by an incompatible version of this crate, or
+    /// isn't a string cache.
+    Incompatible,
+    /// The process creating the segment didn't finish setting it up in time.
+    Timeout,
+}
+
+impl fmt::Display for SharedError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SharedError::Io(e) => write!(f, "shared memory error: {e}"),
+            SharedError::InvalidName => {
+                write!(f, "segment name contains a nul byte")
+            }
+            SharedError::TooSmall => {
+                write!(f, "segment is too small for a string cache")
+            }
+            SharedError::Incompatible => {
+                write!(f, "segment holds an incompatible string cache")
+            }
+            SharedError::Timeout => {
+                write!(f, "timed out waiting for the segment to be set up")
+            }
+        }
+    }
+}
+
+impl std::error::Error for SharedError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SharedError::Io(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SharedError {
+    fn from(e: io::Error) -> SharedError {
+        SharedError::Io(e)
+    }
+}
+
+// Bytes needed for the header and the initial tables.
+fn min_size() -> usize {
+    std::mem::size_of::<Header>().next_multiple_of(TABLE_ALIGN as usize)
+        + BINS * INITIAL_SLOTS * std::mem::size_of::<u64>()
+}
+
+// Holds the lock of a bin.
+struct BinGuard<'a> {
+    bin: &'a Bin,
+}
+
+impl Drop for BinGuard<'_> {
+    fn drop(&mut self) {
+        unsafe { libc::pthread_mutex_unlock(self.bin.lock.get()) };
+    }
+}
+
+impl SharedCache {
+    /// Open the shared string cache in the shared-memory segment `name`,
+    /// creating the segment with `size` bytes if it doesn't exist.
+    ///
+    /// `name` follows the rules of `shm_open()`: it should start with a slash
+    /// and contain no other. The size of an existing segment is kept. Memory
+    /// is only used as strings are added, so `size` can be generous.
+    pub fn open(name: &str, size: usize) -> Result<SharedCache, SharedError> {
+        let name = CString::new(name).map_err(|_| SharedError::InvalidName)?;
+        let mut created = true;
+        // SAFETY: `name` is a valid C string.
+        let mut fd = unsafe {
+            libc::shm_open(
+                name.as_ptr(),
+                libc::O_RDWR | libc::O_CREAT | libc::O_EXCL,
+                0o600,
+            )
+        };
+        if fd < 0
+            && io::Error::last_os_error().kind() == io::ErrorKind::AlreadyExists
+        {
+            created = false;
+            // SAFETY: as above.
+            fd = unsafe { libc::shm_open(name.as_ptr(), libc::O_RDWR, 0) };
+        }
+        if fd < 0 {
+            return Err(io::Error::last_os_error().into());
+        }
+        let res = SharedCache::map(fd, created, size);
+        // SAFETY: `fd` was opened above, and the mapping doesn't need it.
+        unsafe { libc::close(fd) };
+        match res {
+            Ok(cache) if created => {
+                cache.set_up();
+                Ok(cache)
+            }
+            Ok(cache) => cache.wait_ready().map(|_| cache),
+            Err(e) => {
+                // Don't leave a segment behind that nobody will set up.
+                if created {
+                    unsafe { libc::shm_unlink(name.as_ptr()) };
+                }
+                Err(e)
+            }
+        }
+    }
+
+    fn map(
+        fd: libc::c_int,
+        created: bool,
+        size: usize,
+    ) -> Result<SharedCache, SharedError> {
+        let size = if created {
+            if size < min_size() {
+                return Err(SharedError::TooSmall);
+            }
+            // SAFETY: `fd` is open for writing.
+            if unsafe { libc::ftruncate(fd, size as libc::off_t) } != 0 {
+                return Err(io::Error::last_os_error().into());
+            }
+            size
+        } else {
+            // The process creating the segment sizes it right away.
+            let start = Instant::now();
+            loop {
+                let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+                // SAFETY: `stat` is valid for writes.
+                if unsafe { libc::fstat(fd, &mut stat) } != 0 {
+                    return Err(io::Error::last_os_error().into());
+                }
+                if stat.st_size > 0 {
+                    break stat.st_size as usize;
+                }
+                if start.elapsed() > SETUP_TIMEOUT {
+                    return Err(SharedError::Timeout);
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        };
+        if size < min_size() {
+            return Err(SharedError::Incompatible);
+        }
+        // SAFETY: asking for a fresh shared mapping of the segment has no
+        // preconditions.
+        let base = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                size,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_NORESERVE,
+                fd,
+                0,
+            )
+        };
+        if base == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error().into());
+        }
+        Ok(SharedCache {
+            base: NonNull::new(base as *mut u8).expect("mmap returned null"),
+            size,
+        })
+    }
+
+    // Set up a segment that was just created, and zero-filled.
+    fn set_up(&self) {
+        let header = self.base.as_ptr() as *mut Header;
+        unsafe {
+            let mut attr = std::mem::zeroed::<libc::pthread_mutexattr_t>();
+            libc::pthread_mutexattr_init(&mut attr);
+            libc::pthread_mutexattr_setpshared(
+                &mut attr,
+                libc::PTHREAD_PROCESS_SHARED,
+            );
+            libc::pthread_mutexattr_setrobust(
+                &mut attr,
+                libc::PTHREAD_MUTEX_ROBUST,
+            );
+            for bin in &(*header).bins {
+                libc::pthread_mutex_init(bin.lock.get(), &attr);
+            }
+            libc::pthread_mutexattr_destroy(&mut attr);
+
+            (*header)
+                .top
+                .store(std::mem::size_of::<Header>() as u64, Ordering::Relaxed);
+            (*header).version = VERSION;
+            (*header).size = self.size as u64;
+            (*header).mutex_size =
+                std::mem::size_of::<libc::pthread_mutex_t>() as u32;
+            (*header).pointer_width = usize::BITS;
+            (*header).entry_size =
+                std::mem::size_of::<StringCacheEntry>() as u32;
+            (*header).hash_check = crate::hash::hash(HASH_PROBE);
+            (*header).magic = MAGIC;
+        }
+        for bin in &self.header().bins {
+            let table = self
+                .alloc_table(INITIAL_SLOTS)
+                .expect("the segment has room for the initial tables");
+            bin.table.store(table, Ordering::Relaxed);
+        }
+        self.header().state.store(READY, Ordering::Release);
+    }
+
+    // Wait for the process that created the segment to set it up, and check
+    // that it's compatible.
+    fn wait_ready(&self) -> Result<(), SharedError> {
+        let start = Instant::now();
+        while self.header().state.load(Ordering::Acquire) == SETTING_UP {
+            if start.elapsed() > SETUP_TIMEOUT {
+                return Err(SharedError::Timeout);
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        let header = self.header();
+        if header.magic != MAGIC
+            || header.version != VERSION
+            || header.size as usize != self.size
+            || header.mutex_size as usize
+                != std::mem::size_of::<libc::pthread_mutex_t>()
+            || header.pointer_width != usize::BITS
+            || header.entry_size as usize
+                != std::mem::size_of::<StringCacheEntry>()
+            || header.hash_check != crate::hash::hash(HASH_PROBE)
+        {
+            return Err(SharedError::Incompatible);
+        }
+        Ok(())
+    }
+
+    /// Remove the shared-memory segment `name`. Processes that have it open
+    /// keep using it, while the next [`SharedCache::open()`] creates a new
+    /// one.
+    pub fn unlink(name: &str) -> io::Result<()> {
+        let name = CString::new(name)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        // SAFETY: `name` is a valid C string.
+        if unsafe { libc::shm_unlink(name.as_ptr()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    #[inline]
+    fn header(&self) -> &Header {
+        unsafe { &*(self.base.as_ptr() as *const Header) }
+    }
+
+    #[inline]
+    fn at<T>(&self, offset: u64) -> *mut T {
+        unsafe { self.base.as_ptr().add(offset as usize) as *mut T }
+    }
+
+    // Allocate `size` bytes aligned to `align` from the segment.
+    fn alloc(&self, size: usize, align: u64) -> Option<u64> {
+        let top = &self.header().top;
+        let mut current = top.load(Ordering::Relaxed);
+        loop {
+            let offset = current.next_multiple_of(align);
+            let end = offset.checked_add(size as u64)?;
+            if end > self.size as u64 {
+                return None;
+            }
+            match top.compare_exchange_weak(
+                current,
+                end,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => return Some(offset),
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
+    // Allocate an empty table of `slots` slots, a power of two, and return
+    // its word for `Bin::table`.
+    fn alloc_table(&self, slots: usize) -> Option<u64> {
+        let offset =
+            self.alloc(slots * std::mem::size_of::<u64>(), TABLE_ALIGN)?;
+        // The segment is zero-filled, and memory is never reused.
+        Some(offset | slots.trailing_zeros() as u64)
+    }
+
+    // The slots of the table in `word`.
+    #[inline]
+    fn slots(&self, word: u64) -> &[AtomicU64] {
+        let offset = word & !(TABLE_ALIGN - 1);
+        let len = 1 << (word & (TABLE_ALIGN - 1));
+        unsafe { std::slice::from_raw_parts(self.at(offset), len) }
+    }
+
+    #[inline]
+    fn bin(&self, hash: u64) -> &Bin {
+        &self.header().bins[crate::cache::bin_for(hash, BINS)]
+    }
+
+    fn lock<'a>(&self, bin: &'a Bin) -> BinGuard<'a> {
+        let res = unsafe { libc::pthread_mutex_lock(bin.lock.get()) };
+        if res == libc::EOWNERDEAD {
+            // The process holding the lock died, maybe in the middle of an
+            // insert.
+            unsafe { libc::pthread_mutex_consistent(bin.lock.get()) };
+            let slots = self.slots(bin.table.load(Ordering::Relaxed));
+            let len = slots
+                .iter()
+                .filter(|s| s.load(Ordering::Relaxed) != 0)
+                .count();
+            bin.len.store(len as u64, Ordering::Relaxed);
+        } else if res != 0 {
+            panic!(
+                "failed to lock shared string cache: {}",
+                io::Error::from_raw_os_error(res)
+            );
+        }
+        BinGuard { bin }
+    }
+
+    // The `Ustr` for the entry at `offset`.
+    #[inline]
+    fn ustr_at(&self, offset: u64) -> Ustr {
+        Ustr {
+            char_ptr: unsafe {
+                NonNull::new_unchecked(
+                    self.at::<u8>(offset)
+                        .add(std::mem::size_of::<StringCacheEntry>()),
+                )
+            },
+        }
+    }
+
+    // Look for `string` in the table in `word`. Returns the offset of its
+    // entry, or the position of the empty slot it would go in.
+    fn find(&self, word: u64, string: &str, hash: u64) -> Result<u64, usize> {
+        let slots = self.slots(word);
+        let mask = slots.len() - 1;
+        let mut pos = hash as usize & mask;
+        loop {
+            // Acquire pairs with the Release store that filled the slot, so
+            // the entry is fully written.
+            let offset = slots[pos].load(Ordering::Acquire);
+            if offset == 0 {
+                return Err(pos);
+            }
+            let entry: *const StringCacheEntry = self.at(offset);
+            if unsafe { (*entry).hash } == hash
+                && self.ustr_at(offset).as_str() == string
+            {
+                return Ok(offset);
+            }
+            pos = (pos + 1) & mask;
+        }
+    }
+
+    /// Returns the `Ustr` for `string` if it's in the cache.
+    pub fn get(&self, string: &str) -> Option<Ustr> {
+        let hash = crate::hash::hash(string.as_bytes());
+        let bin = self.bin(hash);
+        let word = bin.table.load(Ordering::Acquire);
+        if let Ok(offset) = self.find(word, string, hash) {
+            return Some(self.ustr_at(offset));
+        }
+        // The table may have been replaced since.
+        let _guard = self.lock(bin);
+        let word = bin.table.load(Ordering::Relaxed);
+        self.find(word, string, hash)
+            .ok()
+            .map(|offset| self.ustr_at(offset))
+    }
+
+    /// Returns the `Ustr` for `string`, adding it to the cache if needed.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the segment is full.
+    pub fn intern(&self, string: &str) -> Ustr {
+        self.try_intern(string)
+            .unwrap_or_else(|e| panic!("failed to intern string: {e}"))
+    }
+
+    /// Returns the `Ustr` for `string`, adding it to the cache if needed, or
+    /// [`InternError::OutOfMemory`] if the segment is full.
+    pub fn try_intern(&self, string: &str) -> Result<Ustr, InternError> {
+        let hash = crate::hash::hash(string.as_bytes());
+        let bin = self.bin(hash);
+        let word = bin.table.load(Ordering::Acquire);
+        if let Ok(offset) = self.find(word, string, hash) {
+            return Ok(self.ustr_at(offset));
+        }
+
+        let _guard = self.lock(bin);
+        let mut word = bin.table.load(Ordering::Relaxed);
+        let pos = match self.find(word, string, hash) {
+            Ok(offset) => return Ok(self.ustr_at(offset)),
+            Err(pos) => pos,
+        };
+        let len = bin.len.load(Ordering::Relaxed) as usize;
+        // Keep the load factor at 0.5, like the global cache.
+        let pos = if (len + 1) * 2 > self.slots(word).len() {
+            word = self.grow(bin, word)?;
+            self.find(word, string, hash).unwrap_err()
+        } else {
+            pos
+        };
+
+        let size = std::mem::size_of::<StringCacheEntry>()
+            .checked_add(string.len())
+            .and_then(|size| size.checked_add(1))
+            .ok_or(InternError::OutOfMemory)?;
+        let offset = self
+            .alloc(size, std::mem::align_of::<StringCacheEntry>() as u64)
+            .ok_or(InternError::OutOfMemory)?;
+        unsafe {
+            let entry: *mut StringCacheEntry = self.at(offset);
+            std::ptr::write(entry, StringCacheEntry::new(hash, string.len()));
+            let chars =
+                (entry as *mut u8).add(std::mem::size_of::<StringCacheEntry>());
+            std::ptr::copy_nonoverlapping(string.as_ptr(), chars, string.len());
+            *chars.add(string.len()) = 0;
+        }
+        // Release pairs with the Acquire load in `find()`.
+        self.slots(word)[pos].store(offset, Ordering::Release);
+        bin.len.store(len as u64 + 1, Ordering::Relaxed);
+        Ok(self.ustr_at(offset))
+    }
+
+    // Replace the table in `word` with one twice as big. Must be called with
+    // the bin's lock held.
+    fn grow(&self, bin: &Bin, word: u64) -> Result<u64, InternError> {
+        let old = self.slots(word);
+        let new_word = self
+            .alloc_table(old.len() * 2)
+            .ok_or(InternError::OutOfMemory)?;
+        let new = self.slots(new_word);
+        let mask = new.len() - 1;
+        for slot in old {
+            let offset = slot.load(Ordering::Relaxed);
+            if offset == 0 {
+                continue;
+            }
+            let hash = unsafe { (*self.at::<StringCacheEntry>(offset)).hash };
+            let mut pos = hash as usize & mask;
+            while new[pos].load(Ordering::Relaxed) != 0 {
+                pos = (pos + 1) & mask;
+            }
+            new[pos].store(offset, Ordering::Relaxed);
+        }
+        // Release pairs with the Acquire loads of `Bin::table`, so readers
+        // that see the new table see everything in it.
+        bin.table.store(new_word, Ordering::Release);
+        Ok(new_word)
+    }
+
+    /// Returns the handle of a `Ustr` from this cache, which identifies it in
+    /// every process that has the segment open, or `None` if the `Ustr` comes
+    /// from elsewhere.
+    pub fn handle(&self, u: Ustr) -> Option<u64> {
+        let ptr = u.as_char_ptr() as usize;
+        let base = self.base.as_ptr() as usize;
+        let top = self.header().top.load(Ordering::Acquire) as usize;
+        (ptr > base && ptr < base + top).then(|| (ptr - base) as u64)
+    }
+
+    /// Returns the `Ustr` for a handle given by
+    /// [`handle()`](SharedCache::handle) in any process that has the segment
+    /// open, or `None` if it isn't the handle of a string in the cache.
+    pub fn from_handle(&self, handle: u64) -> Option<Ustr> {
+        let header_size = std::mem::size_of::<StringCacheEntry>() as u64;
+        let top = self.header().top.load(Ordering::Acquire);
+        if !handle
+            .is_multiple_of(std::mem::align_of::<StringCacheEntry>() as u64)
+            || handle < std::mem::size_of::<Header>() as u64 + header_size
+            || handle >= top
+        {
+            return None;
+        }
+        let offset = handle - header_size;
+        // The entry is only trusted once it's been found in its bin's table,
+        // so read its hash without assuming it's an entry.
+        let hash =
+            unsafe { (*self.at::<AtomicU64>(offset)).load(Ordering::Relaxed) };
+        let bin = self.bin(hash);
+        let word = bin.table.load(Ordering::Acquire);
+        if self.contains(word, hash, offset) {
+            return Some(self.ustr_at(offset));
+        }
+        let _guard = self.lock(bin);
+        self.contains(bin.table.load(Ordering::Relaxed), hash, offset)
+            .then(|| self.ustr_at(offset))
+    }
+
+    // Whether the table in `word` has the entry at `offset`, whose string has
+    // the given hash.
+    fn contains(&self, word: u64, hash: u64, offset: u64) -> bool {
+        let slots = self.slots(word);
+        let mask = slots.len() - 1;
+        let mut pos = hash as usize & mask;
+        loop {
+            match slots[pos].load(Ordering::Acquire) {
+                0 => return false,
+                o if o == offset => return true,
+                _ => pos = (pos + 1) & mask,
+            }
+        }
+    }
+
+    /// Returns the number of strings in the cache.
+    pub fn len(&self) -> usize {
+        self.header()
+            .bins
+            .iter()
+            .map(|bin| {
+                let _guard = self.lock(bin);
+                bin.len.load(Ordering::Relaxed) as usize
+            })
+            .sum()
+    }
+
+    /// Returns true if the cache holds no strings.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{collections::HashMap, process::Command};
+
+    // Set in the processes spawned by `test_processes`, to the name of the
+    // segment and the number of the process.
+    const CHILD: &str = "USTR_SHM_TEST_CHILD";
+    const SIZE: usize = 64 << 20;
+
+    // The strings interned by process `i`, overlapping with the others'.
+    fn words(i: usize) -> impl Iterator<Item = String> {
+        (0..5_000).map(move |j| format!("shared{}", (j * (i + 1)) % 3_000))
+    }
+
+    #[test]
+    fn test_processes() {
+        if let Ok(spec) = std::env::var(CHILD) {
+            let (name, i) = spec.split_once(' ').unwrap();
+            let cache = SharedCache::open(name, SIZE).unwrap();
+            if i == "die" {
+                // Exit while holding a lock.
+                let hash = crate::hash::hash(b"orphan");
+                std::mem::forget(cache.lock(cache.bin(hash)));
+                std::process::exit(0);
+            }
+            for w in words(i.parse().unwrap()) {
+                let u = cache.intern(&w);
+                println!("handle {w} {}", cache.handle(u).unwrap());
+            }
+            return;
+        }
+
+        let name = format!("/ustr-test-{}", std::process::id());
+        let _ = SharedCache::unlink(&name);
+        let children: Vec<_> = (0..4)
+            .map(|i| {
+                Command::new(std::env::current_exe().unwrap())
+                    .args(["--exact", "shm::tests::test_processes"])
+                    .arg("--nocapture")
+                    .env(CHILD, format!("{name} {i}"))
+                    .stdout(std::process::Stdio::piped())
+                    .spawn()
+                    .unwrap()
+            })
+            .collect();
+        let cache = SharedCache::open(&name, SIZE).unwrap();
+
+        let mut handles = HashMap::new();
+        for child in children {
+            let child = child.wait_with_output().unwrap();
+            assert!(child.status.success());
+            let out = String::from_utf8(child.stdout).unwrap();
+            for line in out.lines() {
+                let Some(line) = line.strip_prefix("handle ") else {
+                    continue;
+                };
+                let (w, h) = line.split_once(' ').unwrap();
+                let h: u64 = h.parse().unwrap();
+                // Every process got the same entry for the same string.
+                assert_eq!(*handles.entry(w.to_owned()).or_insert(h), h);
+            }
+        }
+        assert_eq!(handles.len(), 3_000);
+        assert_eq!(cache.len(), 3_000);
+        for (w, &h) in &handles {
+            let u = cache.from_handle(h).unwrap();
+            assert_eq!(u, w.as_str());
+            assert_eq!(u.precomputed_hash(), crate::hash::hash(w.as_bytes()));
+            assert_eq!(cache.get(w), Some(u));
+            assert_eq!(cache.intern(w), u);
+            assert_eq!(cache.from_handle(h + 8), None);
+        }
+        assert_eq!(cache.get("missing"), None);
+        assert_eq!(cache.handle(crate::ustr("global")), None);
+        assert_eq!(cache.from_handle(0), None);
+        assert_eq!(cache.from_handle(u64::MAX - 7), None);
+
+        // The lock of a process that died is taken over.
+        let status = Command::new(std::env::current_exe().unwrap())
+            .args(["--exact", "shm::tests::test_processes"])
+            .env(CHILD, format!("{name} die"))
+            .status()
+            .unwrap();
+        assert!(status.success());
+        assert_eq!(cache.intern("orphan"), "orphan");
+        assert_eq!(cache.len(), 3_001);
+
+        // Another process sees the same segment until it's unlinked.
+        SharedCache::unlink(&name).unwrap();
+        let fresh = SharedCache::open(&name, SIZE).unwrap();
+        assert!(fresh.is_empty());
+        SharedCache::unlink(&name).unwrap();
+    }
+}
@@ -1,6 +1,37 @@
-use super::bumpalloc::LeakyBumpAlloc;
+use super::bumpalloc::{Backing, LeakyBumpAlloc};
+use crate::sync::{BinGuard, BinLock};
+use crate::{
+    BinStats, FrozenPolicy, MemoryUsage, Ustr,
+    budget::{self, InternError},
+};
+use alloc::{alloc::Layout, boxed::Box, vec::Vec};
+#[cfg(feature = "profiling")]
+use core::sync::atomic::AtomicU64;
+use core::{
+    sync::atomic::{AtomicPtr, AtomicUsize, Ordering},
+    time::Duration,
+};
+#[cfg(feature = "std")]
+use std::time::Instant;
 
-// `StringCache` stores a `Vec` of pointers to the `StringCacheEntry` structs.
+// Without the standard library there's no clock, so growth pauses all count
+// as zero.
+#[cfg(not(feature = "std"))]
+#[derive(Clone, Copy)]
+struct Instant;
+
+#[cfg(not(feature = "std"))]
+impl Instant {
+    fn now() -> Instant {
+        Instant
+    }
+
+    fn elapsed(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+// `StringCache` stores a table of pointers to the `StringCacheEntry` structs.
 // The actual memory for the `StringCacheEntry` is stored in the LeakyBumpAlloc,
 // and each `Alloc` is rotated out when it's full and a new one twice its size
 // is allocated. The Allocator memory is never freed so our strings essentialy
@@ -10,7 +41,9 @@ use super::bumpalloc::LeakyBumpAlloc;
 // aligned to 8 bytes on a 64-bit system. The 64-bit memoized hash of the string
 // is stored first, then a usize length, then the u8 characters, followed by a
 // null terminator (not included in len), then x<8 bytes of uninitialized memory
-// as padding before the next aligned entry.
+// as padding before the next aligned entry. With the `profiling` feature, two
+// counters of intern calls and lookups sit between the length and the
+// characters.
 //
 //       hash             len       H e l l o , W o r l d !\0
 // |. . . . . . . .|. . . . . . . .|. . . . . . . .|. . . . . . . .|
@@ -22,388 +55,2319 @@ use super::bumpalloc::LeakyBumpAlloc;
 // Allocator and creating a new one when it would overflow -- the `Alloc` itself
 // will just `abort()` if it runs out of memory. Note that we abort() rather
 // than panic because the behaviour of the spinlock in case of a panic while
-// holding the lock is undefined.
+// holding the lock is undefined. For the same reason, errors that are meant to
+// panic, like `FrozenPolicy::Panic`, are returned out of the locked section and
+// only turned into a panic once the lock has been released.
+//
+// Entries created for an `ArcUstr` are reference-counted and get their own
+// allocation rather than living in the `LeakyBumpAlloc`, with the count stored
+// in front of the usual header (see `RcEntry`). Their table slots are tagged by
+// setting the low bit of the pointer. When the last handle is dropped the entry
+// is freed and its slot replaced with a tombstone, which probing skips over and
+// `insert()` reuses. If a `Ustr` is ever handed out for a counted entry it
+// becomes immortal: its count is pinned and its slot untagged.
+//
+// Strings longer than `large_threshold` don't go into the allocators either:
+// each gets an allocation of its own, of exactly the size of its entry, so that
+// a single huge string doesn't make the next allocator twice as big. They have
+// the same layout as any other entry.
 //
-// Thread safety is ensured because we can only access the `StringCache` through
-// the spinlock in the `lazy_static` ref. The initial capacity of the cache is
-// divided evenly among a number of 'bins' or shards each with their own lock,
-// in order to reduce contention.
+// Iterating over the cache walks the entries in each allocator, from the most
+// recent allocation up: each entry's header gives its length, and the next
+// entry starts after the null terminator, rounded up to the alignment. Large
+// entries and counted entries that became immortal aren't in an allocator, so
+// they're kept in lists of their own.
+//
+// Looking up a string that is already in the cache doesn't take a lock. The
+// table is published through an `AtomicPtr` and its slots are atomic, so
+// readers can probe it while another thread is inserting. Everything else is
+// behind a lock in each bin, so writers are serialized: they write an entry
+// completely before storing its pointer in a slot, and when the table grows
+// they build the new one completely before swapping it in. Replaced tables are
+// kept around until the cache is dropped, since a reader may still be probing
+// them. Counted entries can be freed by another thread at any time, so readers
+// never look behind a tagged slot. They probe past it instead, and only take
+// the lock when they don't find the string elsewhere, since the tagged slot
+// may have held it.
+//
+// Growing the table doesn't move all the entries at once, which would stall
+// whichever insert triggered it for milliseconds on a big table. Instead the
+// new table starts out empty and links to the one it replaces (`Table::prev`),
+// and every insert moves the next `MIGRATE_SLOTS` slots' worth of entries over
+// until the old table is empty. In the meantime lookups check the new table
+// first and then the old one. Moved entries are left in the old table, so
+// readers that are still probing it find everything it ever had.
+//
+// The initial capacity of the cache is divided evenly among a number of 'bins'
+// or shards each with their own lock, in order to reduce contention.
+//
+// Aligning to 128 bytes keeps bins on separate cache lines, which gives up to
+// 20% performance improvement.
 #[repr(align(128))]
 pub(crate) struct StringCache {
+    // The current table, read by lookups without taking the lock. Only ever
+    // replaced with the lock held.
+    table: AtomicPtr<Table>,
+    inner: BinLock<Inner>,
+    // What to do when asked for a new entry while frozen. Applied once the
+    // lock has been released, see `apply_frozen_policy()`.
+    frozen_policy: FrozenPolicy,
+}
+
+// The parts of a `StringCache` that are only touched with its lock held.
+pub(crate) struct Inner {
     pub(crate) alloc: LeakyBumpAlloc,
     pub(crate) old_allocs: Vec<LeakyBumpAlloc>,
-    entries: Vec<*mut StringCacheEntry>,
     num_entries: usize,
+    // Number of slots holding a tombstone.
+    num_tombstones: usize,
+    // Number of live reference-counted entries (included in `num_entries`).
+    num_counted: usize,
+    // Bytes in individual allocations of reference-counted entries.
+    rc_bytes: usize,
+    // Entries with their own allocation, for strings longer than
+    // `large_threshold`.
+    large: Vec<*mut StringCacheEntry>,
+    // Bytes in the allocations of `large`.
+    large_bytes: usize,
+    // Length above which a string gets its own allocation.
+    large_threshold: usize,
+    // Size of the first allocator, which `clear()` goes back to.
+    initial_alloc: usize,
+    // Factor by which each new allocator is bigger than the last.
+    growth_factor: usize,
+    // Whether entries count towards the global `Budget`.
+    budgeted: bool,
+    // Set by `freeze()`, after which no entries are added.
+    frozen: bool,
+    // Tables whose entries have all been moved to a newer one, which readers
+    // may still be probing.
+    retired: Vec<*mut Table>,
+    // Next slot of `Table::prev` to be moved into the current table.
+    migrate_pos: usize,
+    // Longest time a single operation spent growing the table.
+    max_pause: Duration,
+    // Number of times `StringCache::lock()` took the lock, and how many of
+    // those found it held by another thread.
+    lock_acquisitions: u64,
+    lock_contentions: u64,
+    // Index of this bin in the global cache, whose size it publishes through
+    // the `metrics` facade.
+    #[cfg(feature = "metrics")]
+    metrics_bin: Option<usize>,
+    // Number of entries when the gauges were last updated.
+    #[cfg(feature = "metrics")]
+    published_entries: usize,
+    // Set when the memory used changed enough that the gauges should be
+    // updated after the next insert.
+    #[cfg(feature = "metrics")]
+    publish_pending: bool,
+    // Whether to record the tags of new entries, which is only done for the
+    // bins of the global cache.
+    #[cfg(feature = "std")]
+    record_tags: bool,
+    // The tag of each entry that was added under one, see `tag::with_tag()`,
+    // and whether the entry is counted.
+    #[cfg(feature = "std")]
+    tags:
+        std::collections::HashMap<*mut StringCacheEntry, (&'static str, bool)>,
+    // Counted entries that were made immortal, which iteration can't find by
+    // walking the allocators.
+    promoted: Vec<*mut StringCacheEntry>,
+}
+
+// Open-addressing table of pointers to entries, probed with triangular steps.
+struct Table {
     mask: usize,
-    // Keep track of all allocated strings for iteration
-    pub(crate) all_strings: Vec<&'static str>,
-    // Padding and aligning to 128 bytes gives up to 20% performance
-    // improvement this actually aligns to 256 bytes because of the Mutex
-    // around it.
-    _pad: [u32; 3],
+    slots: Box<[AtomicPtr<StringCacheEntry>]>,
+    // The table this one replaced, while its entries are still being moved
+    // over. Null once they all have been.
+    prev: AtomicPtr<Table>,
 }
 
-// TODO: make these configurable?
-// Initial size of the StringCache table
-pub(crate) const INITIAL_CAPACITY: usize = 1 << 20;
-// Initial size of the allocator storage (in bytes)
-pub(crate) const INITIAL_ALLOC: usize = 4 << 20;
-// Number of bins (shards) for map
-pub(crate) const BIN_SHIFT: usize = 6;
-pub(crate) const NUM_BINS: usize = 1 << BIN_SHIFT;
-// Shift for top bits to determine bin a hash falls into
-pub(crate) const TOP_SHIFT: usize =
-    8 * std::mem::size_of::<usize>() - BIN_SHIFT;
+// Number of slots of the previous table moved over by each insert while the
+// table is growing. A table of `n` slots grows once its entries and
+// tombstones take up half of it. The new table is the same size if at most a
+// quarter of the slots still hold entries, or else twice the size, and starts
+// out without tombstones. Either way it takes `n / 4` inserts or more before
+// it grows again: removals never add to the load, and neither do inserts into
+// tombstones, which still move the migration along. So this needs to be at
+// least 4 for a migration to always finish before the next one starts, which
+// `test_migration_finishes()` checks.
+const MIGRATE_SLOTS: usize = 128;
+const _: () = assert!(MIGRATE_SLOTS >= 4);
 
-impl StringCache {
-    /// Create a new StringCache with the given starting capacity
-    pub fn new() -> StringCache {
-        let capacity = INITIAL_CAPACITY / NUM_BINS;
-        let alloc = LeakyBumpAlloc::new(
-            INITIAL_ALLOC / NUM_BINS,
-            std::mem::align_of::<StringCacheEntry>(),
-        );
-        StringCache {
-            // Current allocator.
-            alloc,
-            // Old allocators we'll keep around for iteration purposes.
-            // 16 would mean we've allocated 128GB of string storage since we
-            // double each time.
-            old_allocs: Vec::with_capacity(16),
-            // Vector of pointers to the `StringCacheEntry` headers.
-            entries: vec![std::ptr::null_mut(); capacity],
-            num_entries: 0,
+impl Table {
+    // Allocate an empty table with `capacity` slots, a power of two.
+    fn try_new(capacity: usize) -> Result<Box<Table>, InternError> {
+        let layout = Layout::array::<AtomicPtr<StringCacheEntry>>(capacity)
+            .map_err(|_| InternError::OutOfMemory)?;
+        // An empty slot is a null pointer, which is all zeroes, so the slots
+        // can come straight from the allocator. Big tables then come straight
+        // from the OS without having to be touched up front.
+        let slots = unsafe {
+            let ptr = alloc::alloc::alloc_zeroed(layout)
+                as *mut AtomicPtr<StringCacheEntry>;
+            if ptr.is_null() {
+                return Err(InternError::OutOfMemory);
+            }
+            Box::from_raw(core::ptr::slice_from_raw_parts_mut(ptr, capacity))
+        };
+        Ok(Box::new(Table {
             mask: capacity - 1,
-            all_strings: Vec::new(),
-            _pad: [0u32; 3],
+            slots,
+            prev: AtomicPtr::new(core::ptr::null_mut()),
+        }))
+    }
+
+    // Number of slots.
+    #[inline]
+    fn capacity(&self) -> usize {
+        self.mask + 1
+    }
+
+    #[inline]
+    fn slot(&self, pos: usize) -> &AtomicPtr<StringCacheEntry> {
+        // We know pos is in bounds as it's always &ed with the mask.
+        unsafe { self.slots.get_unchecked(pos) }
+    }
+
+    // Number of steps it takes to probe from the slot `hash` starts at to
+    // `pos`.
+    fn probe_distance(&self, hash: u64, pos: usize) -> usize {
+        let mut probe = self.mask & hash as usize;
+        let mut dist = 0;
+        while probe != pos {
+            dist += 1;
+            probe = (probe + dist) & self.mask;
         }
+        dist
     }
 
-    pub(crate) fn get_existing(
-        &self,
-        string: &str,
-        hash: u64,
-    ) -> Option<*const u8> {
+    // Probe for `string` without the lock.
+    #[inline]
+    fn lookup(&self, string: &str, hash: u64) -> Lookup {
         let mut pos = self.mask & hash as usize;
         let mut dist = 0;
+        let mut passed_counted = false;
         loop {
-            let entry = unsafe { self.entries.get_unchecked(pos) };
+            // Acquire pairs with the Release store that published the
+            // entry, so all of it is visible.
+            let entry = self.slot(pos).load(Ordering::Acquire);
             if entry.is_null() {
-                return None;
+                return if passed_counted {
+                    Lookup::NeedsLock
+                } else {
+                    Lookup::Missing
+                };
             }
-            // This is safe as long as entry points to a valid address and the
-            // layout described in the `StringCache` doc comment holds.
-            unsafe {
-                // entry is a `*StringCacheEntry` so offseting by 1 gives us a
-                // pointer to the end of the entry, aka the beginning of the
-                // chars.
-                // As long as the memory is valid and the layout is correct,
-                // we're safe to create a string slice from the chars since
-                // they were copied directly from a valid `str`.
-                let entry_chars = entry.add(1) as *const u8;
-                // if entry is non-null then it must point to a valid
-                // StringCacheEntry
-                let sce = &**entry;
-                if sce.hash == hash
-                    && sce.len == string.len()
-                    && std::str::from_utf8_unchecked(
-                        std::slice::from_raw_parts(entry_chars, sce.len),
-                    ) == string
-                {
-                    // found matching string in the cache already, return it
-                    return Some(entry_chars);
+            if !is_tombstone(entry) {
+                if is_counted(entry) {
+                    // The last handle to it may be dropped at any time, so
+                    // its memory can't be read without the lock. A string is
+                    // only in the table once, so keep looking for an
+                    // immortal entry, and leave this one to the lock if
+                    // there's none.
+                    passed_counted = true;
+                } else if unsafe { entry_matches(entry, string, hash) } {
+                    return Lookup::Found(entry);
                 }
             }
 
-            // Keep looking.
+            // Keep looking. A table that has been replaced can't be full, but
+            // leave it to the lock just in case.
             dist += 1;
-            debug_assert!(dist <= self.mask);
+            if dist > self.mask {
+                return Lookup::NeedsLock;
+            }
             pos = (pos + dist) & self.mask;
         }
     }
 
-    // Insert the given string with its given hash into the cache.
-    pub(crate) fn insert(&mut self, string: &str, hash: u64) -> *const u8 {
+    // Probe for `string` with the lock held.
+    //
+    // Returns `Ok(pos)` with the position of the slot holding the string if
+    // it's in the table. Otherwise returns `Err(pos)` with the position the
+    // string should be inserted at: the first tombstone along the probe
+    // sequence if there was one, or else the empty slot that ended the search.
+    fn find(&self, string: &str, hash: u64) -> Result<usize, usize> {
         let mut pos = self.mask & hash as usize;
         let mut dist = 0;
+        let mut first_tombstone = None;
         loop {
-            let entry = unsafe { self.entries.get_unchecked(pos) };
+            let entry = self.slot(pos).load(Ordering::Relaxed);
             if entry.is_null() {
-                // found empty slot to insert
-                break;
+                return Err(first_tombstone.unwrap_or(pos));
             }
-
-            // This is safe as long as entry points to a valid address and the
-            // layout described in the `StringCache` doc comment holds.
-            unsafe {
-                // entry is a `*StringCacheEntry` so offseting by 1 gives us a
-                // pointer to the end of the entry, aka the beginning of the
-                // chars.
-                // As long as the memory is valid and the layout is correct,
-                // we're safe to create a string slice from the chars since
-                // they were copied directly from a valid `str`.
-                let entry_chars = entry.add(1) as *const u8;
-                // If entry is non-null then it must point to a valid
-                // `StringCacheEntry`.
-                let sce = &**entry;
-                if sce.hash == hash
-                    && sce.len == string.len()
-                    && std::str::from_utf8_unchecked(
-                        std::slice::from_raw_parts(entry_chars, sce.len),
-                    ) == string
-                {
-                    // found matching string in the cache already, return it
-                    return entry_chars;
-                }
+            if is_tombstone(entry) {
+                first_tombstone.get_or_insert(pos);
+            } else if unsafe { entry_matches(untag(entry), string, hash) } {
+                // found matching string in the cache already, return it
+                return Ok(pos);
             }
 
-            // keep looking
+            // Keep looking.
             dist += 1;
             debug_assert!(dist <= self.mask);
             pos = (pos + dist) & self.mask;
         }
+    }
 
-        //
-        // Insert the new string.
-        //
+    // Find the slot holding `entry` (tagged or not) with the lock held.
+    fn find_entry(&self, entry: *mut StringCacheEntry) -> Option<usize> {
+        let hash = unsafe { (*untag(entry)).hash };
+        let mut pos = self.mask & hash as usize;
+        let mut dist = 0;
+        loop {
+            let slot = self.slot(pos).load(Ordering::Relaxed);
+            if slot.is_null() {
+                return None;
+            }
+            if slot == entry {
+                return Some(pos);
+            }
+            dist += 1;
+            debug_assert!(dist <= self.mask);
+            pos = (pos + dist) & self.mask;
+        }
+    }
 
-        // We know pos is in bounds as it's &ed with the mask above.
-        let entry_ptr = unsafe { self.entries.get_unchecked_mut(pos) };
-        // Ddd one to length for null byte.
-        // There's no way we could overflow here in practice since that would
-        // require having allocated a `u64::MAX`-length string, by which time
-        // we'll be using 128-bit pointers and we'll need to rewrite this
-        // crate anyway.
-        let byte_len = string.len() + 1;
-        let alloc_size = std::mem::size_of::<StringCacheEntry>() + byte_len;
-
-        // Rotate allocators when the current one would overflow to keep a
-        // single contiguous bump region per shard (fastest for single-threaded
-        // inserts).
-        let capacity = self.alloc.capacity();
-        let allocated = self.alloc.allocated();
-        if alloc_size
-            .checked_add(allocated)
-            .expect("overflowed alloc_size + allocated")
-            > capacity
-        {
-            let new_capacity = capacity
-                .checked_mul(2)
-                .expect("capacity * 2 overflowed")
-                .max(alloc_size);
-            let old_alloc = std::mem::replace(
-                &mut self.alloc,
-                LeakyBumpAlloc::new(
-                    new_capacity,
-                    std::mem::align_of::<StringCacheEntry>(),
-                ),
-            );
-            self.old_allocs.push(old_alloc);
+    // Store `entry` in slot `pos`, which must be free, with the lock held.
+    fn fill(&self, pos: usize, entry: *mut StringCacheEntry) {
+        // Release so that readers who see the pointer also see the entry.
+        self.slot(pos).store(entry, Ordering::Release);
+    }
+
+    // Store `entry` in the first free slot along its probe sequence. Only
+    // used for entries that are known not to be in the table yet.
+    //
+    // Returns true if this took the place of a tombstone.
+    fn place(&self, entry: *mut StringCacheEntry) -> bool {
+        let hash = unsafe { (*untag(entry)).hash };
+        let mut pos = self.mask & hash as usize;
+        let mut dist = 0;
+        loop {
+            let slot = self.slot(pos).load(Ordering::Relaxed);
+            if slot.is_null() || is_tombstone(slot) {
+                self.fill(pos, entry);
+                return !slot.is_null();
+            }
+            dist += 1;
+            // This should be impossble as the table is never more than half
+            // full.
+            debug_assert!(dist <= self.mask, "Probing wrapped around");
+            pos = (pos + dist) & self.mask;
         }
+    }
 
-        // This is safe as long as:
-        // 1. `alloc_size` is calculated correctly.
-        // 2. The `StringCacheEntry` layout descibed above holds and the memory
-        //    returned by allocate() is prooperly aligned.
-        unsafe {
-            *entry_ptr =
-                self.alloc.allocate(alloc_size) as *mut StringCacheEntry;
-
-            // Write the header.
-            // `entry_ptr` is guaranteed to point to a valid `StringCacheEntry`,
-            // or `alloc.allocate()` would have aborted.
-            std::ptr::write(
-                *entry_ptr,
-                StringCacheEntry {
-                    hash,
-                    len: string.len(),
-                },
-            );
-            // Write the characters after the `StringCacheEntry`.
-            let char_ptr = entry_ptr.add(1) as *mut u8;
-            std::ptr::copy_nonoverlapping(
-                string.as_bytes().as_ptr(),
-                char_ptr,
-                string.len(),
-            );
-            // Write the trailing null.
-            let write_ptr = char_ptr.add(string.len());
-            std::ptr::write(write_ptr, 0u8);
+    // Replace the entry in slot `pos` with a tombstone, with the lock held.
+    fn remove(&self, pos: usize) {
+        self.slot(pos).store(tombstone(), Ordering::Release);
+    }
+}
+
+// Result of probing the table without the lock.
+enum Lookup {
+    // The string's entry, which is immortal.
+    Found(*mut StringCacheEntry),
+    // The string is not in the cache.
+    Missing,
+    // The string wasn't found, but the probe passed over reference-counted
+    // entries that may hold it, which can only be looked at with the lock
+    // held.
+    NeedsLock,
+}
+
+// Defaults for `CacheConfig`, see `configure()` to change these.
+// Initial size of the StringCache table
+pub(crate) const INITIAL_CAPACITY: usize = 1 << 20;
+// Initial size of the allocator storage (in bytes)
+pub(crate) const INITIAL_ALLOC: usize = 4 << 20;
+// Number of bins (shards) for map, just one if the cache is only used by one
+// thread at a time
+#[cfg(not(feature = "single-thread"))]
+pub(crate) const BIN_SHIFT: usize = 6;
+#[cfg(feature = "single-thread")]
+pub(crate) const BIN_SHIFT: usize = 0;
+pub(crate) const NUM_BINS: usize = 1 << BIN_SHIFT;
+// Length above which a string gets its own allocation (in bytes)
+pub(crate) const LARGE_STRING_THRESHOLD: usize = 16 << 10;
+// Number of entries a bin adds or drops between updates of its gauges
+#[cfg(feature = "metrics")]
+const PUBLISH_INTERVAL: usize = 1024;
+
+impl StringCache {
+    /// Create a new StringCache with the given starting table capacity (a
+    /// power of two), allocator size in bytes and allocator growth factor.
+    pub fn new(
+        capacity: usize,
+        initial_alloc: usize,
+        growth_factor: usize,
+    ) -> StringCache {
+        debug_assert!(capacity.is_power_of_two() && capacity >= 2);
+        let alloc = LeakyBumpAlloc::new(
+            initial_alloc,
+            core::mem::align_of::<StringCacheEntry>(),
+            Backing::Heap,
+        );
+        let table = Table::try_new(capacity).unwrap_or_else(|_| {
+            alloc::alloc::handle_alloc_error(
+                Layout::array::<AtomicPtr<StringCacheEntry>>(capacity).unwrap(),
+            )
+        });
+        StringCache {
+            table: AtomicPtr::new(Box::into_raw(table)),
+            inner: BinLock::new(Inner {
+                // Current allocator.
+                alloc,
+                // Old allocators we'll keep around for iteration purposes.
+                // 16 would mean we've allocated 128GB of string storage since
+                // we double each time.
+                old_allocs: Vec::with_capacity(16),
+                num_entries: 0,
+                num_tombstones: 0,
+                num_counted: 0,
+                rc_bytes: 0,
+                large: Vec::new(),
+                large_bytes: 0,
+                large_threshold: LARGE_STRING_THRESHOLD,
+                initial_alloc,
+                growth_factor,
+                budgeted: false,
+                frozen: false,
+                retired: Vec::new(),
+                migrate_pos: 0,
+                max_pause: Duration::ZERO,
+                lock_acquisitions: 0,
+                lock_contentions: 0,
+                #[cfg(feature = "metrics")]
+                metrics_bin: None,
+                #[cfg(feature = "metrics")]
+                published_entries: 0,
+                #[cfg(feature = "metrics")]
+                publish_pending: false,
+                #[cfg(feature = "std")]
+                record_tags: false,
+                #[cfg(feature = "std")]
+                tags: std::collections::HashMap::new(),
+                promoted: Vec::new(),
+            }),
+            frozen_policy: FrozenPolicy::Error,
+        }
+    }
 
-            self.num_entries += 1;
+    // Make this cache count its entries towards the global `Budget`.
+    pub(crate) fn budgeted(mut self) -> StringCache {
+        self.inner.get_mut().budgeted = true;
+        self
+    }
 
-            // Track the string for iteration
-            let s = std::str::from_utf8_unchecked(std::slice::from_raw_parts(
-                char_ptr,
-                string.len(),
-            ));
-            self.all_strings.push(s);
+    // Record the tags strings are added under, as a bin of the global cache.
+    #[cfg(feature = "std")]
+    pub(crate) fn record_tags(mut self) -> StringCache {
+        self.inner.get_mut().record_tags = true;
+        self
+    }
 
-            // We want to keep an 0.5 load factor for the map, so grow if we've
-            // exceeded that.
-            if self.num_entries * 2 > self.mask {
-                self.grow();
-            }
+    // Publish the size of this cache through the `metrics` facade, as bin
+    // `bin` of the global cache.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn metrics_bin(mut self, bin: usize) -> StringCache {
+        self.inner.get_mut().metrics_bin = Some(bin);
+        self
+    }
+
+    // Give strings longer than `len` bytes an allocation of their own.
+    pub(crate) fn large_string_threshold(mut self, len: usize) -> StringCache {
+        self.inner.get_mut().large_threshold = len;
+        self
+    }
+
+    // Take the string storage from ranges of `reserve` bytes of address
+    // space, whose pages are only committed as they're used.
+    #[cfg(all(feature = "mmap", unix))]
+    pub(crate) fn mmap_arena(
+        mut self,
+        reserve: usize,
+        huge_pages: bool,
+    ) -> StringCache {
+        let inner = self.inner.get_mut();
+        let alloc = LeakyBumpAlloc::new(
+            reserve,
+            core::mem::align_of::<StringCacheEntry>(),
+            Backing::Mmap { huge_pages },
+        );
+        // Nothing has been allocated from the heap-backed allocator yet.
+        unsafe { core::mem::replace(&mut inner.alloc, alloc).clear() };
+        inner.initial_alloc = reserve;
+        self
+    }
 
-            char_ptr
+    // Give back the budget taken by every entry in the cache, which is being
+    // thrown away before anything was handed out from it.
+    #[cfg(all(feature = "mmap", unix))]
+    pub(crate) fn release_all_budget(&mut self) {
+        let mut sc = self.lock();
+        // Moved entries stay in the previous table, so only count the current
+        // one.
+        sc.migrate(usize::MAX);
+        for slot in sc.table().slots.iter() {
+            let e = slot.load(Ordering::Relaxed);
+            if !e.is_null() && !is_tombstone(e) {
+                let len = unsafe { (*untag(e)).len };
+                sc.release_budget(entry_layout(len).map_or(0, |l| l.size()));
+            }
         }
     }
 
-    // Double the size of the map storage.
+    // Take over the entries in `allocs`, which must be the regions of a
+    // persistent cache file that belong to this bin, and allocate new entries
+    // from the last of them. Returns the number of entries taken over, which
+    // is short of the number in `allocs` if a string is in them twice.
     //
-    // This is safe as long as:
-    // - The in-memory layout of the `StringCacheEntry` is correct.
-    //
-    // If there's not enough memory for the new entry table, it will just abort
-    pub(crate) unsafe fn grow(&mut self) {
-        unsafe {
-            let new_mask = self.mask * 2 + 1;
+    // This is safe as long as the allocators hold nothing but entries with
+    // valid strings, and the cache is empty.
+    #[cfg(all(feature = "mmap", unix))]
+    pub(crate) unsafe fn adopt_allocs(
+        &mut self,
+        mut allocs: Vec<LeakyBumpAlloc>,
+    ) -> Result<usize, InternError> {
+        let ranges: Vec<EntryRange> = allocs
+            .iter()
+            .map(|a| unsafe { EntryRange::new(a.allocated_range()) })
+            .collect();
+        let count: usize = ranges.iter().cloned().map(Iterator::count).sum();
+        // Size the table for all of them up front, rather than growing it as
+        // they go in.
+        let table = self.table.get_mut();
+        let capacity = (count * 2 + 1).next_power_of_two();
+        if unsafe { (**table).capacity() } < capacity {
+            let new = Box::into_raw(Table::try_new(capacity)?);
+            drop(unsafe { Box::from_raw(core::mem::replace(table, new)) });
+        }
 
-            let mut new_entries: std::vec::Vec<*mut StringCacheEntry> =
-                vec![std::ptr::null_mut(); new_mask + 1];
+        let inner = self.inner.get_mut();
+        if let Some(current) = allocs.pop() {
+            // Nothing has been allocated from the allocator it replaces.
+            unsafe { core::mem::replace(&mut inner.alloc, current).clear() };
+        }
+        inner.old_allocs.extend(allocs);
+        let mut sc = self.lock();
+        let mut adopted = 0;
+        for entry in ranges.into_iter().flatten() {
+            if unsafe { sc.adopt(entry as *mut StringCacheEntry)? } {
+                adopted += 1;
+            }
+        }
+        Ok(adopted)
+    }
 
-            // copy the existing map into the new map
-            let mut to_copy = self.num_entries;
-            for e in self.entries.iter_mut() {
-                if e.is_null() {
-                    continue;
-                }
+    // Set what happens when a new string is inserted after `freeze()`.
+    pub(crate) fn frozen_policy(mut self, policy: FrozenPolicy) -> StringCache {
+        self.frozen_policy = policy;
+        self
+    }
 
-                // Start of the entry is the hash.
-                let hash = *(*e as *const u64);
-                let mut pos = (hash as usize) & new_mask;
-                let mut dist = 0;
-                loop {
-                    if new_entries[pos].is_null() {
-                        // Here's an empty slot to put the pointer in.
-                        break;
-                    }
+    // Stop adding entries to the cache. Before that, finish growing the table
+    // and make every reference-counted entry immortal, so that no lookup ever
+    // needs the lock again.
+    pub(crate) fn freeze(&self) {
+        let mut sc = self.lock();
+        sc.migrate(usize::MAX);
+        for slot in sc.table().slots.iter() {
+            let entry = slot.load(Ordering::Relaxed);
+            if !entry.is_null() && !is_tombstone(entry) && is_counted(entry) {
+                unsafe { sc.make_immortal(slot) };
+            }
+        }
+        sc.inner.frozen = true;
+    }
+
+    // Take the lock, for anything that changes the cache.
+    #[inline]
+    pub(crate) fn lock(&self) -> LockedCache<'_> {
+        // Trying first costs nothing more than taking the lock when it's
+        // free, and tells us when it wasn't.
+        let mut inner = match self.inner.try_lock() {
+            Some(inner) => inner,
+            None => {
+                let mut inner = self.inner.lock();
+                inner.lock_contentions += 1;
+                inner
+            }
+        };
+        inner.lock_acquisitions += 1;
+        LockedCache {
+            table: &self.table,
+            inner,
+        }
+    }
 
-                    dist += 1;
-                    // This should be impossble as we've allocated twice as many
-                    // slots as we have entries.
-                    debug_assert!(dist <= new_mask, "Probing wrapped around");
-                    pos = pos.wrapping_add(dist) & new_mask;
+    // Look for `string` without taking the lock.
+    #[inline]
+    fn lookup(&self, string: &str, hash: u64) -> Lookup {
+        // Acquire pairs with the Release store that published the table.
+        let table = unsafe { &*self.table.load(Ordering::Acquire) };
+        // Load this before probing `table`: if the migration finishes while
+        // we're probing, seeing null here means everything was moved into
+        // `table` before we started.
+        let prev = table.prev.load(Ordering::Acquire);
+        match table.lookup(string, hash) {
+            // An entry that hasn't been moved yet is only in `prev`.
+            res @ (Lookup::Missing | Lookup::NeedsLock) if !prev.is_null() => {
+                match unsafe { (*prev).lookup(string, hash) } {
+                    Lookup::Missing => res,
+                    prev_res => prev_res,
                 }
+            }
+            res => res,
+        }
+    }
+
+    pub(crate) fn get_existing(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Option<*const u8> {
+        match self.lookup(string, hash) {
+            Lookup::Found(entry) => Some(unsafe { entry_chars(entry) }),
+            Lookup::Missing => None,
+            Lookup::NeedsLock => self.lock().get_existing(string, hash),
+        }
+    }
+
+    // Insert the given string with its given hash into the cache.
+    //
+    // Panics if the string can't be interned, see `try_insert()`.
+    pub(crate) fn insert(&self, string: &str, hash: u64) -> *const u8 {
+        self.try_insert(string, hash)
+            .unwrap_or_else(|e| panic!("failed to intern string: {e}"))
+    }
+
+    // Insert the given string with its given hash into the cache, or return
+    // why it can't be. Strings that are already in the cache are always
+    // returned, and only take the lock if they are reference-counted.
+    pub(crate) fn try_insert(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Result<*const u8, InternError> {
+        match self.lookup(string, hash) {
+            Lookup::Found(entry) => Ok(unsafe { entry_chars(entry) }),
+            _ => {
+                let res = self.lock().try_insert(string, hash);
+                self.apply_frozen_policy(res)
+            }
+        }
+    }
+
+    // Panic on `InternError::Frozen` if the frozen policy says so. This is
+    // done with the lock released, see the top of this file.
+    fn apply_frozen_policy<T>(
+        &self,
+        res: Result<T, InternError>,
+    ) -> Result<T, InternError> {
+        if let (Err(InternError::Frozen), FrozenPolicy::Panic) =
+            (&res, self.frozen_policy)
+        {
+            panic!("failed to intern string: {}", InternError::Frozen);
+        }
+        res
+    }
+
+    // Look up the given string without ever taking the lock. Strings that
+    // are only held by `ArcUstr`s are reported as missing.
+    #[inline]
+    pub(crate) fn get_unlocked(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Option<*const u8> {
+        match self.lookup(string, hash) {
+            Lookup::Found(entry) => Some(unsafe { entry_chars(entry) }),
+            _ => None,
+        }
+    }
 
-                new_entries[pos] = *e;
-                to_copy -= 1;
-                if to_copy == 0 {
-                    break;
+    // Insert the strings in `strings` picked by `batch`, storing the chars of
+    // each in `out` at the same index. The lock is taken once for all of
+    // them.
+    //
+    // Panics if a string can't be interned, like `insert()`.
+    pub(crate) fn insert_batch<'a>(
+        &self,
+        batch: impl IntoIterator<Item = (usize, &'a str, u64)>,
+        mut inserted: impl FnMut(usize, *const u8),
+    ) {
+        let mut sc = self.lock();
+        for (i, string, hash) in batch {
+            match sc.try_insert(string, hash) {
+                Ok(ptr) => inserted(i, ptr),
+                Err(e) => {
+                    // Don't panic with the lock held, see the top of this
+                    // file.
+                    drop(sc);
+                    panic!("failed to intern string: {e}");
                 }
             }
+        }
+    }
+
+    // Look up the given string for an `ArcUstr`, see
+    // `LockedCache::get_existing_counted()`.
+    pub(crate) fn get_existing_counted(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Option<(*const u8, bool)> {
+        match self.lookup(string, hash) {
+            Lookup::Found(entry) => {
+                Some((unsafe { entry_chars(entry) }, false))
+            }
+            Lookup::Missing => None,
+            Lookup::NeedsLock => self.lock().get_existing_counted(string, hash),
+        }
+    }
 
-            self.entries = new_entries;
-            self.mask = new_mask;
+    // Insert the given string as a reference-counted entry, unless it's
+    // already in the cache, see `LockedCache::try_insert_counted()`.
+    pub(crate) fn try_insert_counted(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Result<(*const u8, bool), InternError> {
+        match self.lookup(string, hash) {
+            Lookup::Found(entry) => Ok((unsafe { entry_chars(entry) }, false)),
+            _ => {
+                let res = self.lock().try_insert_counted(string, hash);
+                self.apply_frozen_policy(res)
+            }
         }
     }
 
     // This is only called by `clear()` during tests to clear the cache between
     // runs. **DO NOT CALL THIS**.
-    pub(crate) unsafe fn clear(&mut self) {
+    pub(crate) unsafe fn clear(&self) {
+        let mut sc = self.lock();
+        // just zero all the pointers that have already been set
+        let table = sc.table();
+        for slot in table.slots.iter() {
+            slot.store(core::ptr::null_mut(), Ordering::Relaxed);
+        }
+        let prev = table.prev.swap(core::ptr::null_mut(), Ordering::Relaxed);
+        let inner = &mut *sc.inner;
+        if !prev.is_null() {
+            inner.retired.push(prev);
+        }
+        inner.migrate_pos = 0;
+        inner.max_pause = Duration::ZERO;
+        inner.lock_acquisitions = 0;
+        inner.lock_contentions = 0;
+        inner.frozen = false;
+        inner.num_entries = 0;
+        inner.num_tombstones = 0;
+        inner.num_counted = 0;
+        inner.rc_bytes = 0;
+        #[cfg(feature = "std")]
+        inner.tags.clear();
+        inner.promoted.clear();
         unsafe {
-            // just zero all the pointers that have already been set
-            std::ptr::write_bytes(self.entries.as_mut_ptr(), 0, self.mask + 1);
-            self.num_entries = 0;
-            self.all_strings.clear();
-            for a in self.old_allocs.iter_mut() {
+            inner.free_large();
+            for a in inner.old_allocs.iter_mut() {
                 a.clear();
             }
-            self.old_allocs = Vec::new();
-            self.alloc.clear();
-            self.alloc = LeakyBumpAlloc::new(
-                INITIAL_ALLOC / NUM_BINS,
-                std::mem::align_of::<StringCacheEntry>(),
-            );
+            inner.old_allocs = Vec::new();
+            inner.alloc.clear();
+        }
+        inner.alloc = LeakyBumpAlloc::new(
+            inner.initial_alloc,
+            core::mem::align_of::<StringCacheEntry>(),
+            inner.alloc.backing(),
+        );
+        #[cfg(feature = "metrics")]
+        inner.publish_metrics();
+    }
+
+    // Free all the memory owned by this cache. Used when dropping a
+    // `LocalInterner`; the cache must not be used afterwards.
+    //
+    // This is safe as long as no pointers returned by `insert()` or
+    // `get_existing()` are used after this call.
+    pub(crate) unsafe fn release(&mut self) {
+        let inner = self.inner.get_mut();
+        unsafe {
+            inner.free_large();
+            for a in inner.old_allocs.iter_mut() {
+                a.clear();
+            }
+            inner.old_allocs.clear();
+            inner.alloc.clear();
         }
     }
 
     pub(crate) fn total_allocated(&self) -> usize {
-        self.alloc.allocated()
-            + self
-                .old_allocs
-                .iter()
-                .map(LeakyBumpAlloc::allocated)
-                .sum::<usize>()
+        self.memory_usage().allocated()
     }
 
     pub(crate) fn total_capacity(&self) -> usize {
-        self.alloc.capacity()
-            + self
-                .old_allocs
-                .iter()
-                .map(LeakyBumpAlloc::capacity)
-                .sum::<usize>()
+        self.memory_usage().capacity()
+    }
+
+    pub(crate) fn memory_usage(&self) -> MemoryUsage {
+        self.inner.lock().memory_usage()
+    }
+
+    // Add the bytes of the entries of each tag to `out`.
+    #[cfg(feature = "std")]
+    pub(crate) fn tag_bytes(
+        &self,
+        out: &mut std::collections::HashMap<&'static str, usize>,
+    ) {
+        let inner = self.inner.lock();
+        for (&entry, &(tag, counted)) in &inner.tags {
+            // Counted entries are only freed with the lock held, and drop
+            // out of `tags` then. They keep their header once promoted.
+            let len = unsafe { (*entry).len };
+            let layout = if counted {
+                RcEntry::layout(len)
+            } else {
+                entry_layout(len)
+            };
+            let size = layout.map_or(0, |l| l.size());
+            *out.entry(tag).or_default() += size;
+        }
+    }
+
+    // Update the gauges of this cache now, rather than after the next insert.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn publish_metrics(&self) {
+        self.inner.lock().publish_metrics();
     }
 
     pub(crate) fn num_entries(&self) -> usize {
-        self.num_entries
+        self.inner.lock().num_entries
+    }
+
+    // Longest time a single operation spent growing the table.
+    pub(crate) fn max_pause(&self) -> Duration {
+        self.inner.lock().max_pause
+    }
+
+    // Describe the shape of this cache's table and storage. The table is
+    // walked with the lock held, but the arenas only hold immortal entries,
+    // so they're walked after it's released.
+    pub(crate) fn stats(&self) -> BinStats {
+        let mut stats = BinStats::default();
+        let mut arenas = Vec::new();
+        let mut tail_bytes = 0;
+        let mut probed = 0;
+        let mut total_distance = 0;
+        let mut full = 0;
+        {
+            let inner = self.inner.lock();
+            // Tables are only replaced with the lock held.
+            let table = unsafe { &*self.table.load(Ordering::Relaxed) };
+            let prev = table.prev.load(Ordering::Relaxed);
+            // Entries that haven't been moved yet are only found in the
+            // previous table, from `migrate_pos` on.
+            let prev_table = (!prev.is_null())
+                .then(|| (unsafe { &*prev }, inner.migrate_pos));
+            let tables = [Some((table, 0)), prev_table];
+            for (i, (t, start)) in tables.into_iter().flatten().enumerate() {
+                for (pos, slot) in t.slots.iter().enumerate().skip(start) {
+                    let e = slot.load(Ordering::Relaxed);
+                    if e.is_null() || is_tombstone(e) {
+                        continue;
+                    }
+                    // Counted entries are only freed with the lock held.
+                    let entry = unsafe { &*untag(e) };
+                    let distance = t.probe_distance(entry.hash, pos);
+                    stats.max_probe_distance =
+                        stats.max_probe_distance.max(distance);
+                    total_distance += distance;
+                    probed += 1;
+                    if i == 0 {
+                        full += 1;
+                    }
+                    stats.length_histogram[length_bucket(entry.len)] += 1;
+                }
+            }
+            stats.entries = inner.num_entries;
+            stats.table_size = table.capacity();
+            stats.tombstones = inner.num_tombstones;
+            stats.migrating = !prev.is_null();
+            stats.max_growth_pause = inner.max_pause;
+            stats.lock_acquisitions = inner.lock_acquisitions;
+            stats.lock_contentions = inner.lock_contentions;
+            for a in &inner.old_allocs {
+                tail_bytes += a.committed() - a.allocated();
+            }
+            for a in inner.old_allocs.iter().chain([&inner.alloc]) {
+                arenas.push((a.allocated(), a.allocated_range()));
+            }
+        }
+        stats.load_factor = full as f64 / stats.table_size as f64;
+        if probed > 0 {
+            stats.mean_probe_distance = total_distance as f64 / probed as f64;
+        }
+        stats.arenas = arenas.len();
+        stats.tail_bytes = tail_bytes;
+        for (allocated, range) in arenas {
+            // This is safe as every allocator holds nothing but entries,
+            // which live forever.
+            let used: usize = unsafe { EntryRange::new(range) }
+                .map(|e| {
+                    core::mem::size_of::<StringCacheEntry>()
+                        + unsafe { (*e).len }
+                        + 1
+                })
+                .sum();
+            stats.padding_bytes += allocated - used;
+        }
+        stats
+    }
+
+    // Append the regions holding every immortal entry in this cache to `out`
+    // and return the number of entries in them. Entries added later are
+    // outside these regions, so they can be walked without the lock.
+    pub(crate) fn snapshot(&self, out: &mut Vec<EntryRange>) -> usize {
+        let inner = self.inner.lock();
+        // This is safe as every allocator holds nothing but entries.
+        unsafe {
+            out.extend(
+                inner
+                    .old_allocs
+                    .iter()
+                    .chain([&inner.alloc])
+                    .map(|a| EntryRange::new(a.allocated_range())),
+            );
+            out.extend(
+                inner
+                    .large
+                    .iter()
+                    .chain(&inner.promoted)
+                    .map(|&e| EntryRange::single(e)),
+            );
+        }
+        inner.num_entries - inner.num_counted
     }
 }
 
 impl Default for StringCache {
     fn default() -> StringCache {
-        StringCache::new()
+        StringCache::new(
+            INITIAL_CAPACITY / NUM_BINS,
+            INITIAL_ALLOC / NUM_BINS,
+            2,
+        )
     }
 }
 
-// We are safe to be `Send` but not `Sync` (we get Sync by wrapping in a mutex).
-unsafe impl Send for StringCache {}
-
-#[doc(hidden)]
-pub struct StringCacheIterator {
-    pub(crate) strings: Vec<&'static str>,
-    pub(crate) current: usize,
+impl Drop for StringCache {
+    fn drop(&mut self) {
+        // We have exclusive access, so nobody can be probing any table now.
+        // The strings themselves are leaked, or freed by `release()`.
+        unsafe {
+            let table = Box::from_raw(*self.table.get_mut());
+            let prev = table.prev.load(Ordering::Relaxed);
+            if !prev.is_null() {
+                drop(Box::from_raw(prev));
+            }
+            drop(table);
+            for table in self.inner.get_mut().retired.drain(..) {
+                drop(Box::from_raw(table));
+            }
+        }
+    }
 }
 
-impl Iterator for StringCacheIterator {
-    type Item = &'static str;
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.current < self.strings.len() {
-            let s = self.strings[self.current];
-            self.current += 1;
-            Some(s)
-        } else {
-            None
+// The raw pointers in here are only used with the lock held.
+unsafe impl Send for Inner {}
+
+impl Inner {
+    fn memory_usage(&self) -> MemoryUsage {
+        let allocs = || self.old_allocs.iter().chain([&self.alloc]);
+        MemoryUsage {
+            arena_allocated: allocs().map(LeakyBumpAlloc::allocated).sum(),
+            arena_capacity: allocs().map(LeakyBumpAlloc::committed).sum(),
+            large_strings: self.large.len(),
+            large_bytes: self.large_bytes,
+            counted_bytes: self.rc_bytes,
+        }
+    }
+
+    // Set the gauges of this bin to its current size. Gauges are set rather
+    // than adjusted, so they catch up with whatever happened before a
+    // recorder was installed.
+    #[cfg(feature = "metrics")]
+    fn publish_metrics(&mut self) {
+        let Some(bin) = self.metrics_bin else {
+            return;
+        };
+        self.published_entries = self.num_entries;
+        self.publish_pending = false;
+        let usage = self.memory_usage();
+        let bin = bin.to_string();
+        metrics::gauge!("ustr_entries", "bin" => bin.clone())
+            .set(self.num_entries as f64);
+        metrics::gauge!("ustr_allocated_bytes", "bin" => bin.clone())
+            .set(usage.allocated() as f64);
+        metrics::gauge!("ustr_capacity_bytes", "bin" => bin)
+            .set(usage.capacity() as f64);
+    }
+
+    // Count one `event` of this bin, and update its gauges after the next
+    // insert.
+    #[cfg(feature = "metrics")]
+    fn count_event(&mut self, event: &'static str) {
+        if let Some(bin) = self.metrics_bin {
+            metrics::counter!(event, "bin" => bin.to_string()).increment(1);
+            self.publish_pending = true;
+        }
+    }
+    // Free the allocations of large entries.
+    //
+    // This is safe as long as no pointers to them are used afterwards.
+    unsafe fn free_large(&mut self) {
+        for entry in self.large.drain(..) {
+            unsafe {
+                let layout = entry_layout((*entry).len)
+                    .expect("layout was valid when the entry was created");
+                alloc::alloc::dealloc(entry as *mut u8, layout);
+            }
         }
+        self.large_bytes = 0;
     }
 }
 
-/// Internal string cache entry with guaranteed memory layout for FFI
-/// compatibility.
-///
-/// # Memory Layout Guarantee
-///
-/// This struct has a stable C-compatible memory layout (`#[repr(C)]`) with the
-/// following guarantees:
-///
-/// ```text
-/// Offset  | Size    | Field | Description
-/// --------|---------|-------|-------------
-/// 0       | 8 bytes | hash  | 64-bit precomputed hash of the string
-/// 8       | 8 bytes | len   | Length of the string in bytes (64-bit systems)
-///         |         |       | or 4 bytes on 32-bit systems
-/// 16      | N bytes | data  | UTF-8 string bytes (not part of struct, follows immediately after)
-/// 16+N    | 1 byte  | null  | Null terminator for C compatibility
-/// 16+N+1  | 0-7     | pad   | Padding to ensure 8-byte alignment for next entry
-/// ```
-///
-/// This layout is guaranteed stable across versions for FFI compatibility.
-/// The string data immediately follows the struct in memory, making it safe to:
-/// - Pass to C functions expecting null-terminated strings
-/// - Calculate offsets for direct memory access
-/// - Iterate over cache entries
-///
-/// # Safety
-///
-/// The memory layout is critical for safety. The `Ustr` type stores a pointer
-/// to the character data (offset 16 in the layout above), and calculates the
-/// location of this header by subtracting `sizeof(StringCacheEntry)`.
-#[repr(C)]
-#[derive(Clone)]
-pub(crate) struct StringCacheEntry {
+// A `StringCache` with its lock held.
+pub(crate) struct LockedCache<'a> {
+    table: &'a AtomicPtr<Table>,
+    inner: BinGuard<'a, Inner>,
+}
+
+impl<'a> LockedCache<'a> {
+    // The current table. Tables are only replaced with the lock held and are
+    // never freed while the cache is borrowed, so this stays valid for `'a`.
+    #[inline]
+    fn table(&self) -> &'a Table {
+        unsafe { &*self.table.load(Ordering::Relaxed) }
+    }
+
+    // The table whose entries are being moved into the current one, if any.
+    #[inline]
+    fn prev(&self) -> Option<&'a Table> {
+        unsafe { self.table().prev.load(Ordering::Relaxed).as_ref() }
+    }
+
+    // Look for `string` in both tables.
+    //
+    // Returns `Ok(slot)` with the slot holding the string if it's in the
+    // cache, otherwise `Err(pos)` with the position in the current table the
+    // string should be inserted at, see `Table::find()`.
+    fn find(
+        &self,
+        string: &str,
+        hash: u64,
+    ) -> Result<&'a AtomicPtr<StringCacheEntry>, usize> {
+        let table = self.table();
+        let pos = match table.find(string, hash) {
+            Ok(pos) => return Ok(table.slot(pos)),
+            Err(pos) => pos,
+        };
+        // Entries that haven't been moved yet are only in the old table.
+        if let Some(prev) = self.prev()
+            && let Ok(prev_pos) = prev.find(string, hash)
+        {
+            return Ok(prev.slot(prev_pos));
+        }
+        Err(pos)
+    }
+
+    pub(crate) fn get_existing(
+        &mut self,
+        string: &str,
+        hash: u64,
+    ) -> Option<*const u8> {
+        self.find(string, hash).ok().map(|slot| unsafe {
+            entry_chars(self.make_immortal(slot)) as *const u8
+        })
+    }
+
+    // Insert the given string with its given hash into the cache, or return
+    // why it can't be. Strings that are already in the cache are always
+    // returned.
+    pub(crate) fn try_insert(
+        &mut self,
+        string: &str,
+        hash: u64,
+    ) -> Result<*const u8, InternError> {
+        let pos = match self.find(string, hash) {
+            Ok(slot) => {
+                return Ok(unsafe { entry_chars(self.make_immortal(slot)) });
+            }
+            Err(pos) => pos,
+        };
+        self.check_frozen()?;
+
+        //
+        // Insert the new string.
+        //
+
+        let layout = entry_layout(string.len())?;
+        self.reserve_budget(string.len(), layout.size())?;
+        let (pos, entry) =
+            match self.make_room(pos, string, hash).and_then(|pos| {
+                Ok((pos, self.allocate_entry(string.len(), layout)?))
+            }) {
+                Ok(res) => res,
+                Err(e) => {
+                    self.release_budget(layout.size());
+                    return Err(e);
+                }
+            };
+
+        // This is safe as long as the `StringCacheEntry` layout descibed above
+        // holds and `entry` points to `layout.size()` properly aligned bytes.
+        unsafe {
+            let char_ptr = write_entry(entry, string, hash);
+            self.inner.alloc.publish();
+            self.fill_slot(pos, entry);
+            Ok(char_ptr)
+        }
+    }
+
+    // Add an entry that is already written, see
+    // `StringCache::adopt_allocs()`. Entries whose string is already in the
+    // cache are skipped.
+    //
+    // This is safe as long as `entry` is an immortal entry holding a valid
+    // string.
+    #[cfg(all(feature = "mmap", unix))]
+    unsafe fn adopt(
+        &mut self,
+        entry: *mut StringCacheEntry,
+    ) -> Result<bool, InternError> {
+        let u = unsafe { ustr_from_entry(entry) };
+        let (string, hash) = (u.as_str(), u.precomputed_hash());
+        let Err(pos) = self.find(string, hash) else {
+            return Ok(false);
+        };
+        let size = entry_layout(string.len())?.size();
+        self.reserve_budget(string.len(), size)?;
+        match self.make_room(pos, string, hash) {
+            Ok(pos) => unsafe { self.fill_slot(pos, entry) },
+            Err(e) => {
+                self.release_budget(size);
+                return Err(e);
+            }
+        }
+        Ok(true)
+    }
+
+    // Allocate the memory for an entry holding a string of `len` bytes, laid
+    // out as given by `entry_layout()`. Large strings get an allocation of
+    // their own, others come from the current allocator.
+    fn allocate_entry(
+        &mut self,
+        len: usize,
+        layout: Layout,
+    ) -> Result<*mut StringCacheEntry, InternError> {
+        if len > self.inner.large_threshold {
+            let entry = unsafe { alloc::alloc::alloc(layout) };
+            if entry.is_null() {
+                return Err(InternError::OutOfMemory);
+            }
+            let entry = entry as *mut StringCacheEntry;
+            self.inner.large.push(entry);
+            self.inner.large_bytes += layout.size();
+            #[cfg(feature = "metrics")]
+            {
+                self.inner.publish_pending = true;
+            }
+            return Ok(entry);
+        }
+        self.reserve_alloc(layout.size())?;
+        // The allocator has room for the entry, or `allocate()` would abort,
+        // but its pages may fail to be committed.
+        let entry = unsafe { self.inner.alloc.allocate(layout.size())? };
+        Ok(entry as *mut StringCacheEntry)
+    }
+
+    // Rotate allocators when the current one would overflow to keep a single
+    // contiguous bump region per shard (fastest for single-threaded inserts).
+    fn reserve_alloc(&mut self, alloc_size: usize) -> Result<(), InternError> {
+        let inner = &mut *self.inner;
+        let capacity = inner.alloc.capacity();
+        let allocated = inner.alloc.allocated();
+        if alloc_size
+            .checked_add(allocated)
+            .ok_or(InternError::OutOfMemory)?
+            > capacity
+        {
+            let new_capacity = capacity
+                .checked_mul(inner.growth_factor)
+                .ok_or(InternError::OutOfMemory)?
+                .max(alloc_size);
+            #[cfg(feature = "tracing")]
+            let _span = tracing::debug_span!(
+                "rotate_arena",
+                allocated,
+                capacity,
+                new_capacity,
+                arenas = inner.old_allocs.len() + 1,
+            )
+            .entered();
+            let new_alloc = LeakyBumpAlloc::try_new(
+                new_capacity,
+                core::mem::align_of::<StringCacheEntry>(),
+                inner.alloc.backing(),
+            )
+            .ok_or(InternError::OutOfMemory)?;
+            let old_alloc = core::mem::replace(&mut inner.alloc, new_alloc);
+            inner.old_allocs.push(old_alloc);
+            #[cfg(feature = "metrics")]
+            inner.count_event("ustr_arena_rotations");
+        }
+        Ok(())
+    }
+
+    // Make sure the table has room for one more entry in slot `pos`, as found
+    // by `find()`. Returns the slot to use, which changes if the table had to
+    // grow.
+    fn make_room(
+        &mut self,
+        pos: usize,
+        string: &str,
+        hash: u64,
+    ) -> Result<usize, InternError> {
+        // Reusing a tombstone doesn't change the load.
+        if is_tombstone(self.table().slot(pos).load(Ordering::Relaxed)) {
+            return Ok(pos);
+        }
+        // We want to keep an 0.5 load factor for the map, so grow if we'd
+        // exceed that. Tombstones count towards the load as they lengthen
+        // probe sequences just the same, and entries that haven't been moved
+        // from the previous table yet will need a slot too.
+        if (self.inner.num_entries + self.inner.num_tombstones + 1) * 2
+            > self.table().capacity()
+        {
+            self.grow()?;
+            return Ok(self.table().find(string, hash).unwrap_err());
+        }
+        Ok(pos)
+    }
+
+    fn reserve_budget(
+        &self,
+        len: usize,
+        bytes: usize,
+    ) -> Result<(), InternError> {
+        if self.inner.budgeted {
+            budget::reserve(len, bytes)
+        } else {
+            Ok(())
+        }
+    }
+
+    // Refuse to add an entry if the cache is frozen. Whether that panics is
+    // up to the caller, once the lock is released.
+    fn check_frozen(&self) -> Result<(), InternError> {
+        if self.inner.frozen {
+            Err(InternError::Frozen)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn release_budget(&self, bytes: usize) {
+        if self.inner.budgeted {
+            budget::release(bytes);
+        }
+    }
+
+    // Look up the given string for an `ArcUstr`.
+    //
+    // Returns the chars and whether the entry is reference-counted. If it is,
+    // its count has been incremented on behalf of the caller.
+    pub(crate) fn get_existing_counted(
+        &mut self,
+        string: &str,
+        hash: u64,
+    ) -> Option<(*const u8, bool)> {
+        self.find(string, hash)
+            .ok()
+            .map(|slot| unsafe { acquire_counted(slot) })
+    }
+
+    // Insert the given string as a reference-counted entry, unless it's
+    // already in the cache. Returns the same as `get_existing_counted()`.
+    pub(crate) fn try_insert_counted(
+        &mut self,
+        string: &str,
+        hash: u64,
+    ) -> Result<(*const u8, bool), InternError> {
+        let pos = match self.find(string, hash) {
+            Ok(slot) => return Ok(unsafe { acquire_counted(slot) }),
+            Err(pos) => pos,
+        };
+        self.check_frozen()?;
+
+        // Counted entries get their own allocation so that their memory can
+        // be returned when the last handle is dropped.
+        let layout = RcEntry::layout(string.len())?;
+        self.reserve_budget(string.len(), layout.size())?;
+        let (pos, rc_entry) = match self.make_room(pos, string, hash) {
+            Ok(pos) => (pos, unsafe { alloc::alloc::alloc(layout) }),
+            Err(e) => {
+                self.release_budget(layout.size());
+                return Err(e);
+            }
+        };
+        if rc_entry.is_null() {
+            self.release_budget(layout.size());
+            return Err(InternError::OutOfMemory);
+        }
+
+        unsafe {
+            let rc_entry = rc_entry as *mut RcEntry;
+            core::ptr::write(
+                &raw mut (*rc_entry).refcount,
+                AtomicUsize::new(1),
+            );
+            let entry = &raw mut (*rc_entry).entry;
+            let char_ptr = write_entry(entry, string, hash);
+            self.inner.num_counted += 1;
+            self.inner.rc_bytes += layout.size();
+            self.fill_slot(pos, tag(entry));
+            Ok((char_ptr, true))
+        }
+    }
+
+    // Remove a reference-counted entry whose count has dropped to zero and
+    // free its memory.
+    //
+    // This is safe as long as `entry` was created by `try_insert_counted()`
+    // on this cache and there are no handles to it left.
+    pub(crate) unsafe fn remove_counted(
+        &mut self,
+        entry: *mut StringCacheEntry,
+    ) {
+        unsafe {
+            let layout = RcEntry::layout((*entry).len)
+                .expect("layout was valid when the entry was created");
+            let size = layout.size();
+            // While the table is growing the entry may be in the old table,
+            // the new one or both. Readers never look behind a tagged slot,
+            // so it's fine to free the entry as soon as it's been replaced.
+            let tagged = tag(entry);
+            let table = self.table();
+            let in_table = table.find_entry(tagged);
+            let prev = self.prev();
+            let in_prev = prev.and_then(|prev| prev.find_entry(tagged));
+            if let Some(pos) = in_table {
+                table.remove(pos);
+                self.inner.num_tombstones += 1;
+            }
+            if let (Some(prev), Some(pos)) = (prev, in_prev) {
+                prev.remove(pos);
+            }
+            // If it's in neither, the cache must have been cleared.
+            if in_table.is_some() || in_prev.is_some() {
+                self.inner.num_entries -= 1;
+                self.inner.num_counted -= 1;
+                self.inner.rc_bytes -= size;
+                #[cfg(feature = "std")]
+                self.inner.tags.remove(&entry);
+                self.release_budget(size);
+            }
+            alloc::alloc::dealloc(
+                RcEntry::from_entry(entry) as *mut u8,
+                layout,
+            );
+        }
+    }
+
+    // A `Ustr` is about to be handed out for the entry in `slot`, so make sure
+    // it lives forever. Reference-counted entries are pinned by giving them a
+    // count that can never drop to zero.
+    unsafe fn make_immortal(
+        &mut self,
+        slot: &AtomicPtr<StringCacheEntry>,
+    ) -> *mut StringCacheEntry {
+        unsafe {
+            let tagged = slot.load(Ordering::Relaxed);
+            let entry = untag(tagged);
+            if is_counted(tagged) {
+                (*RcEntry::from_entry(entry))
+                    .refcount
+                    .store(IMMORTAL_REFCOUNT, Ordering::Relaxed);
+                // From here on readers may use the entry without the lock. If
+                // the table is growing, a tagged copy may be left in the other
+                // table, which just sends readers to the lock.
+                slot.store(entry, Ordering::Release);
+                self.inner.num_counted -= 1;
+                self.inner.rc_bytes -= RcEntry::layout((*entry).len)
+                    .expect("layout was valid when the entry was created")
+                    .size();
+                self.inner.promoted.push(entry);
+            }
+            entry
+        }
+    }
+
+    // Store a newly written entry in slot `pos`, which must be empty or a
+    // tombstone and have been checked by `make_room()`.
+    unsafe fn fill_slot(&mut self, pos: usize, entry: *mut StringCacheEntry) {
+        let table = self.table();
+        if is_tombstone(table.slot(pos).load(Ordering::Relaxed)) {
+            self.inner.num_tombstones -= 1;
+        }
+        table.fill(pos, entry);
+        self.inner.num_entries += 1;
+        #[cfg(feature = "std")]
+        if self.inner.record_tags
+            && let Some(tag) = crate::tag::current()
+        {
+            let counted = is_counted(entry);
+            self.inner.tags.insert(untag(entry), (tag, counted));
+        }
+        #[cfg(feature = "metrics")]
+        if self.inner.publish_pending
+            || self
+                .inner
+                .num_entries
+                .abs_diff(self.inner.published_entries)
+                >= PUBLISH_INTERVAL
+        {
+            self.inner.publish_metrics();
+        }
+
+        // Now that `pos` is taken, move the growth along.
+        if self.prev().is_some() {
+            let start = Instant::now();
+            self.migrate(MIGRATE_SLOTS);
+            self.record_pause(start);
+        }
+    }
+
+    // Start growing the table: publish an empty table that replaces the
+    // current one, doubling its size unless most of the load was tombstones.
+    // The entries are moved over by later inserts, see `migrate()`.
+    //
+    // If there's not enough memory for the new entry table, the table is left
+    // as it is.
+    fn grow(&mut self) -> Result<(), InternError> {
+        let start = Instant::now();
+        #[cfg(feature = "tracing")]
+        let _span = tracing::debug_span!(
+            "grow_table",
+            entries = self.inner.num_entries,
+            tombstones = self.inner.num_tombstones,
+            capacity = self.table().capacity(),
+        )
+        .entered();
+        // Only one migration can be in flight. Inserts always finish the last
+        // one before the table is full again, see `MIGRATE_SLOTS`, but a new
+        // table would lose whatever is left in the old one, so make sure.
+        debug_assert!(self.prev().is_none(), "grew before migration finished");
+        self.migrate(usize::MAX);
+
+        let old = self.table();
+        let new_capacity = if self.inner.num_entries * 4 > old.capacity() {
+            old.capacity() * 2
+        } else {
+            old.capacity()
+        };
+        #[cfg(feature = "metrics")]
+        self.inner.count_event("ustr_table_grows");
+
+        let new = Table::try_new(new_capacity)?;
+        let old_ptr = self.table.load(Ordering::Relaxed);
+        new.prev.store(old_ptr, Ordering::Relaxed);
+        // Release pairs with the Acquire load in `StringCache::lookup()`.
+        self.table.store(Box::into_raw(new), Ordering::Release);
+        self.inner.migrate_pos = 0;
+        self.inner.num_tombstones = 0;
+        self.record_pause(start);
+        Ok(())
+    }
+
+    // Move the entries in the next `max_slots` slots of the previous table
+    // into the current one, and retire the previous table once it's done.
+    fn migrate(&mut self, max_slots: usize) {
+        let Some(prev) = self.prev() else {
+            return;
+        };
+        let table = self.table();
+        let start = self.inner.migrate_pos;
+        let end = start.saturating_add(max_slots).min(prev.capacity());
+        for slot in &prev.slots[start..end] {
+            let e = slot.load(Ordering::Relaxed);
+            if !e.is_null() && !is_tombstone(e) && table.place(e) {
+                self.inner.num_tombstones -= 1;
+            }
+        }
+        self.inner.migrate_pos = end;
+
+        if end == prev.capacity() {
+            // Everything has been moved. Release pairs with the Acquire load
+            // in `StringCache::lookup()`, so readers that see null here also
+            // see all the moved entries.
+            let prev =
+                table.prev.swap(core::ptr::null_mut(), Ordering::Release);
+            self.inner.retired.push(prev);
+            #[cfg(feature = "tracing")]
+            tracing::debug!(
+                capacity = table.capacity(),
+                max_pause = ?self.inner.max_pause,
+                "finished growing table",
+            );
+        }
+    }
+
+    fn record_pause(&mut self, start: Instant) {
+        let pause = start.elapsed();
+        if pause > self.inner.max_pause {
+            self.inner.max_pause = pause;
+        }
+    }
+}
+
+// Take a new reference to the entry in `slot` for an `ArcUstr`.
+//
+// This is only safe with the lock held.
+unsafe fn acquire_counted(
+    slot: &AtomicPtr<StringCacheEntry>,
+) -> (*const u8, bool) {
+    unsafe {
+        let entry = slot.load(Ordering::Relaxed);
+        if is_counted(entry) {
+            let entry = untag(entry);
+            (*RcEntry::from_entry(entry))
+                .refcount
+                .fetch_add(1, Ordering::Relaxed);
+            (entry_chars(entry) as *const u8, true)
+        } else {
+            (entry_chars(entry) as *const u8, false)
+        }
+    }
+}
+
+/// An iterator over the strings in the global cache, returned by
+/// [`string_cache_iter()`](crate::string_cache_iter).
+///
+/// The strings are read straight from the cache's memory as the iterator
+/// advances, so iterating a large cache doesn't need memory for a copy of it.
+/// Only the strings that were in the cache when the iterator was created are
+/// yielded.
+pub struct StringCacheIterator {
+    // Regions still to be walked, in reverse order, with the one being walked
+    // last.
+    pub(crate) ranges: Vec<EntryRange>,
+    pub(crate) remaining: usize,
+}
+
+impl Iterator for StringCacheIterator {
+    type Item = Ustr;
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let range = self.ranges.last_mut()?;
+            match range.next() {
+                Some(entry) => {
+                    self.remaining -= 1;
+                    return Some(unsafe { ustr_from_entry(entry) });
+                }
+                None => {
+                    self.ranges.pop();
+                }
+            }
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for StringCacheIterator {}
+
+impl core::iter::FusedIterator for StringCacheIterator {}
+
+#[cfg(feature = "rayon")]
+impl StringCacheIterator {
+    /// Turn the strings not yet yielded into a parallel iterator.
+    ///
+    /// Each chunk of the cache's string storage is scanned by a single task,
+    /// so the work is spread over as many tasks as the cache has chunks.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rayon::prelude::*;
+    /// use ustr::ustr;
+    /// # unsafe { ustr::_clear_cache() };
+    ///
+    /// ustr("alpha");
+    /// ustr("beta");
+    /// let total: usize =
+    ///     ustr::string_cache_iter().par_iter().map(|u| u.len()).sum();
+    /// assert_eq!(total, 9);
+    /// ```
+    pub fn par_iter(self) -> ParStringCacheIter {
+        ParStringCacheIter {
+            ranges: self.ranges,
+        }
+    }
+}
+
+#[cfg(feature = "rayon")]
+impl rayon::iter::IntoParallelIterator for StringCacheIterator {
+    type Iter = ParStringCacheIter;
+    type Item = Ustr;
+
+    fn into_par_iter(self) -> Self::Iter {
+        self.par_iter()
+    }
+}
+
+/// A parallel iterator over the strings in the global cache, returned by
+/// [`StringCacheIterator::par_iter()`].
+#[cfg(feature = "rayon")]
+pub struct ParStringCacheIter {
+    ranges: Vec<EntryRange>,
+}
+
+#[cfg(feature = "rayon")]
+impl rayon::iter::ParallelIterator for ParStringCacheIter {
+    type Item = Ustr;
+
+    fn drive_unindexed<C>(self, consumer: C) -> C::Result
+    where
+        C: rayon::iter::plumbing::UnindexedConsumer<Self::Item>,
+    {
+        use rayon::prelude::*;
+        self.ranges
+            .into_par_iter()
+            .flat_map_iter(|range| {
+                range.map(|entry| unsafe { ustr_from_entry(entry) })
+            })
+            .drive_unindexed(consumer)
+    }
+}
+
+// A `Ustr` for an immortal entry.
+#[inline]
+unsafe fn ustr_from_entry(entry: *const StringCacheEntry) -> Ustr {
+    Ustr {
+        char_ptr: unsafe {
+            core::ptr::NonNull::new_unchecked(entry_chars(entry))
+        },
+    }
+}
+
+// A region of memory holding nothing but immortal entries, walked from the
+// front. Each entry starts where the previous one's header, chars and null
+// end, rounded up to the alignment.
+#[derive(Clone)]
+pub(crate) struct EntryRange {
+    ptr: *const u8,
+    end: *const u8,
+}
+
+// The entries are immutable and live forever.
+unsafe impl Send for EntryRange {}
+unsafe impl Sync for EntryRange {}
+
+impl EntryRange {
+    // The entries allocated from a `LeakyBumpAlloc`, as given by
+    // `allocated_range()`.
+    //
+    // This is safe as long as everything allocated from it is an entry with
+    // the layout described in the `StringCache` doc comment, and the entries
+    // are never freed.
+    pub(crate) unsafe fn new((ptr, end): (*const u8, *const u8)) -> EntryRange {
+        EntryRange { ptr, end }
+    }
+
+    // Just the given entry, which must be immortal.
+    unsafe fn single(entry: *const StringCacheEntry) -> EntryRange {
+        unsafe {
+            let end = entry_chars(entry).add((*entry).len + 1);
+            EntryRange {
+                ptr: entry as *const u8,
+                end,
+            }
+        }
+    }
+}
+
+impl Iterator for EntryRange {
+    type Item = *const StringCacheEntry;
+    fn next(&mut self) -> Option<Self::Item> {
+        let remaining = self.end as usize - self.ptr as usize;
+        // Every entry has a header, so any bytes left over after the last one
+        // are just padding.
+        if remaining < core::mem::size_of::<StringCacheEntry>() {
+            return None;
+        }
+        let entry = self.ptr as *const StringCacheEntry;
+        // Each entry in an allocator was bumped down from an aligned pointer,
+        // so the next one starts at its end rounded up to the alignment. Only
+        // the entry at the very end of the region may have less padding than
+        // that.
+        let size = (core::mem::size_of::<StringCacheEntry>()
+            + unsafe { (*entry).len }
+            + 1)
+        .next_multiple_of(core::mem::align_of::<StringCacheEntry>());
+        self.ptr = unsafe { self.ptr.add(size.min(remaining)) };
+        Some(entry)
+    }
+}
+
+/// Header of a string cache entry, directly followed by the string.
+///
+/// # Memory Layout
+///
+/// The header is `#[repr(C)]`, and an entry is laid out as:
+///
+/// ```text
+/// Offset  | Size    | Field   | Description
+/// --------|---------|---------|-------------
+/// 0       | 8 bytes | hash    | 64-bit precomputed hash of the string
+/// 8       | word    | len     | Length of the string in bytes
+/// ...     | 16 bytes| counters| Intern and lookup counts (`profiling` only)
+/// H       | N bytes | data    | UTF-8 string bytes, H = size_of::<Self>()
+/// H+N     | 1 byte  | null    | Null terminator for C compatibility
+/// H+N+1   | 0-7     | pad     | Padding to the alignment of the next entry
+/// ```
+///
+/// The size of the header depends on the target and on the features the crate
+/// is built with. Since Cargo unifies features across a build, another crate
+/// in the dependency graph can change it, so the layout is not part of the
+/// API and isn't stable across versions or builds. The only invariant is that
+/// the string data starts `size_of::<StringCacheEntry>()` bytes after the
+/// start of the header, with the null terminator right after it.
+///
+/// # Safety
+///
+/// The `Ustr` type stores a pointer to the character data, and finds this
+/// header by subtracting `size_of::<StringCacheEntry>()` from it. Anything
+/// that reads entries, such as C code given the pointer of a `Ustr` or a
+/// process sharing the cache, must be built with the same header size.
+#[repr(C)]
+pub(crate) struct StringCacheEntry {
     /// Precomputed hash of the string for O(1) comparisons
     pub(crate) hash: u64,
     /// Length of the string in bytes (not including null terminator)
     pub(crate) len: usize,
+    /// Number of times the string was interned (`profiling` feature only)
+    #[cfg(feature = "profiling")]
+    pub(crate) interns: AtomicU64,
+    /// Number of times the string was looked up without interning it
+    /// (`profiling` feature only)
+    #[cfg(feature = "profiling")]
+    pub(crate) lookups: AtomicU64,
+}
+
+impl StringCacheEntry {
+    pub(crate) fn new(hash: u64, len: usize) -> StringCacheEntry {
+        StringCacheEntry {
+            hash,
+            len,
+            #[cfg(feature = "profiling")]
+            interns: AtomicU64::new(0),
+            #[cfg(feature = "profiling")]
+            lookups: AtomicU64::new(0),
+        }
+    }
+}
+
+/// Header of a reference-counted entry, used by `ArcUstr`.
+///
+/// The reference count sits directly in front of the usual
+/// [`StringCacheEntry`], so the string itself has the same layout as any other
+/// entry and can be read through a plain `Ustr`-style pointer.
+#[repr(C)]
+pub(crate) struct RcEntry {
+    pub(crate) refcount: AtomicUsize,
+    pub(crate) entry: StringCacheEntry,
+}
+
+impl RcEntry {
+    // Layout of the allocation holding a counted entry for a string of `len`
+    // bytes, including the null terminator.
+    pub(crate) fn layout(len: usize) -> Result<Layout, InternError> {
+        core::mem::size_of::<RcEntry>()
+            .checked_add(len)
+            .and_then(|size| size.checked_add(1))
+            .and_then(|size| {
+                Layout::from_size_align(size, core::mem::align_of::<RcEntry>())
+                    .ok()
+            })
+            .ok_or(InternError::OutOfMemory)
+    }
+
+    // Get the `RcEntry` that the given header is part of.
+    //
+    // This is only valid for entries created by `insert_counted()`.
+    pub(crate) unsafe fn from_entry(
+        entry: *const StringCacheEntry,
+    ) -> *const RcEntry {
+        unsafe {
+            entry
+                .byte_sub(core::mem::offset_of!(RcEntry, entry))
+                .cast::<RcEntry>()
+        }
+    }
+}
+
+// Reference count given to counted entries that have been made immortal.
+// Anything above `usize::MAX / 2` is treated as immortal, so handles that are
+// still being cloned or dropped can't move it back into the normal range.
+pub(crate) const IMMORTAL_REFCOUNT: usize = usize::MAX / 4 * 3;
+
+#[inline]
+pub(crate) fn is_immortal(refcount: usize) -> bool {
+    refcount > usize::MAX / 2
+}
+
+// Set on the slots of reference-counted entries.
+const COUNTED_TAG: usize = 1;
+
+// Marks a slot whose entry was removed.
+#[inline]
+fn tombstone() -> *mut StringCacheEntry {
+    core::ptr::without_provenance_mut(usize::MAX)
+}
+
+#[inline]
+// Bucket of `BinStats::length_histogram` that strings of length `len` go in.
+fn length_bucket(len: usize) -> usize {
+    let bits = (usize::BITS - len.leading_zeros()) as usize;
+    bits.min(BinStats::LENGTH_BUCKETS - 1)
+}
+
+fn is_tombstone(slot: *mut StringCacheEntry) -> bool {
+    slot.addr() == usize::MAX
+}
+
+// Only meaningful for slots that are neither null nor a tombstone.
+#[inline]
+fn is_counted(slot: *mut StringCacheEntry) -> bool {
+    slot.addr() & COUNTED_TAG != 0
+}
+
+#[inline]
+fn tag(entry: *mut StringCacheEntry) -> *mut StringCacheEntry {
+    entry.map_addr(|a| a | COUNTED_TAG)
+}
+
+#[inline]
+fn untag(slot: *mut StringCacheEntry) -> *mut StringCacheEntry {
+    slot.map_addr(|a| a & !COUNTED_TAG)
+}
+
+// Layout of an entry for a string of `len` bytes, including the null
+// terminator.
+fn entry_layout(len: usize) -> Result<Layout, InternError> {
+    core::mem::size_of::<StringCacheEntry>()
+        .checked_add(len)
+        .and_then(|size| size.checked_add(1))
+        .and_then(|size| {
+            Layout::from_size_align(
+                size,
+                core::mem::align_of::<StringCacheEntry>(),
+            )
+            .ok()
+        })
+        .ok_or(InternError::OutOfMemory)
+}
+
+// entry is a `*StringCacheEntry` so offseting by 1 gives us a pointer to the
+// end of the entry, aka the beginning of the chars.
+#[inline]
+unsafe fn entry_chars(entry: *const StringCacheEntry) -> *mut u8 {
+    unsafe { entry.add(1) as *mut u8 }
+}
+
+// Check whether `entry` holds `string`.
+//
+// This is safe as long as entry points to a valid address and the layout
+// described in the `StringCache` doc comment holds.
+#[inline]
+unsafe fn entry_matches(
+    entry: *const StringCacheEntry,
+    string: &str,
+    hash: u64,
+) -> bool {
+    unsafe {
+        // As long as the memory is valid and the layout is correct, we're safe
+        // to create a string slice from the chars since they were copied
+        // directly from a valid `str`.
+        let sce = &*entry;
+        sce.hash == hash
+            && sce.len == string.len()
+            && core::str::from_utf8_unchecked(core::slice::from_raw_parts(
+                entry_chars(entry),
+                sce.len,
+            )) == string
+    }
+}
+
+// Write the header, characters and trailing null of a new entry, returning a
+// pointer to the characters.
+//
+// This is safe as long as `entry` points to properly aligned memory with room
+// for the header followed by `string.len() + 1` bytes.
+unsafe fn write_entry(
+    entry: *mut StringCacheEntry,
+    string: &str,
+    hash: u64,
+) -> *mut u8 {
+    unsafe {
+        // Write the header.
+        core::ptr::write(entry, StringCacheEntry::new(hash, string.len()));
+        // Write the characters after the `StringCacheEntry`.
+        let char_ptr = entry_chars(entry);
+        core::ptr::copy_nonoverlapping(
+            string.as_bytes().as_ptr(),
+            char_ptr,
+            string.len(),
+        );
+        // Write the trailing null.
+        core::ptr::write(char_ptr.add(string.len()), 0u8);
+        char_ptr
+    }
+}
+
+#[test]
+fn test_incremental_growth() {
+    let sc = StringCache::new(4, 256, 2);
+    let words: Vec<String> = (0..5_000).map(|i| format!("word{i}")).collect();
+    let mut counted = Vec::new();
+    let mut removed = std::collections::HashSet::new();
+    let mut saw_migration = false;
+
+    for (i, w) in words.iter().enumerate() {
+        let hash = crate::hash::hash(w.as_bytes());
+        if i % 3 == 0 {
+            let (ptr, is_counted) = sc.try_insert_counted(w, hash).unwrap();
+            assert!(is_counted);
+            let entry = unsafe { (ptr as *mut StringCacheEntry).sub(1) };
+            counted.push((i, entry));
+        } else {
+            sc.insert(w, hash);
+        }
+
+        let migrating = sc.lock().prev().is_some();
+        saw_migration |= migrating;
+        // While entries are being moved, everything must still be found.
+        if migrating || i % 500 == 0 {
+            for (j, w) in words[..=i].iter().enumerate() {
+                if removed.contains(&j) {
+                    continue;
+                }
+                let hash = crate::hash::hash(w.as_bytes());
+                let found = if j % 3 == 0 {
+                    sc.lock().find(w, hash).is_ok()
+                } else {
+                    sc.get_existing(w, hash).is_some()
+                };
+                assert!(found, "lost {w} after inserting {i} strings");
+            }
+        }
+
+        // Drop half of the counted entries again, some of them while the
+        // table is growing.
+        if i % 7 == 0
+            && let Some((j, entry)) = counted.pop()
+        {
+            let mut sc = sc.lock();
+            unsafe { sc.remove_counted(entry) };
+            let hash = crate::hash::hash(words[j].as_bytes());
+            assert!(sc.find(&words[j], hash).is_err());
+            removed.insert(j);
+        }
+    }
+
+    assert!(saw_migration);
+    assert!(sc.max_pause() > Duration::ZERO);
+    assert_eq!(sc.num_entries(), words.len() - removed.len());
+
+    // Clean up the counted entries that are left.
+    for (_, entry) in counted {
+        unsafe { sc.lock().remove_counted(entry) };
+    }
+}
+
+#[test]
+fn test_lookup_past_counted() {
+    // Counted entries are only looked at with the lock held, but lookups
+    // without it probe past them to find the immortal entries behind.
+    let sc = StringCache::new(1 << 10, 256, 2);
+    let hash = |s: &str| crate::hash::hash(s.as_bytes());
+    let words: Vec<String> = (0..400).map(|i| format!("w{i}")).collect();
+    let mut counted = Vec::new();
+    for (i, w) in words.iter().enumerate() {
+        if i % 2 == 0 {
+            let (ptr, _) = sc.try_insert_counted(w, hash(w)).unwrap();
+            counted.push(unsafe { (ptr as *mut StringCacheEntry).sub(1) });
+        } else {
+            sc.insert(w, hash(w));
+        }
+    }
+
+    let table = unsafe { &*sc.table.load(Ordering::Relaxed) };
+    let mut behind_counted = 0;
+    for (i, w) in words.iter().enumerate() {
+        let lookup = sc.lookup(w, hash(w));
+        if i % 2 == 0 {
+            assert!(matches!(lookup, Lookup::NeedsLock), "{w}");
+            continue;
+        }
+        assert!(matches!(lookup, Lookup::Found(_)), "{w}");
+        let first = table.slot(table.mask & hash(w) as usize);
+        let first = first.load(Ordering::Relaxed);
+        if !first.is_null() && !is_tombstone(first) && is_counted(first) {
+            behind_counted += 1;
+        }
+    }
+    assert!(behind_counted > 0);
+    // Strings that aren't in the cache may be behind a counted entry too,
+    // so the lock has the last word on them.
+    for i in 0..400 {
+        let w = format!("missing{i}");
+        assert!(!matches!(sc.lookup(&w, hash(&w)), Lookup::Found(_)));
+        assert!(sc.get_existing(&w, hash(&w)).is_none());
+    }
+
+    for entry in counted {
+        unsafe { sc.lock().remove_counted(entry) };
+    }
+}
+
+#[test]
+fn test_migration_finishes() {
+    // Counted entries that are dropped right away leave tombstones behind,
+    // which make the table rehash at the same size as well as double.
+    let sc = StringCache::new(2, 256, 2);
+    let hash = |s: &str| crate::hash::hash(s.as_bytes());
+    let (mut grew, mut rehashed) = (0, 0);
+    for i in 0..50_000 {
+        let table = sc.table.load(Ordering::Relaxed);
+        let migrating = sc.lock().prev().is_some();
+        let capacity = unsafe { (*table).capacity() };
+
+        let w = format!("w{i}");
+        if i % 4 == 0 {
+            sc.insert(&w, hash(&w));
+        } else {
+            let (ptr, _) = sc.try_insert_counted(&w, hash(&w)).unwrap();
+            let entry = unsafe { (ptr as *mut StringCacheEntry).sub(1) };
+            unsafe { sc.lock().remove_counted(entry) };
+        }
+
+        let new = sc.table.load(Ordering::Relaxed);
+        if new != table {
+            assert!(!migrating, "grew while migrating after {i} strings");
+            grew += 1;
+            if unsafe { (*new).capacity() } == capacity {
+                rehashed += 1;
+            }
+        }
+    }
+    assert!(grew > rehashed && rehashed > 0, "{grew} {rehashed}");
+    assert_eq!(sc.num_entries(), 12_500);
+}
+
+#[test]
+fn test_walk_allocators() {
+    // An odd allocator size leaves the end of each allocator unaligned, and a
+    // small one makes sure there are plenty of them.
+    let mut sc = StringCache::new(8, 61, 2);
+    let words: Vec<String> = (0..2_000)
+        .map(|i| "x".repeat(i % 23) + &i.to_string())
+        .collect();
+    for w in &words {
+        sc.insert(w, crate::hash::hash(w.as_bytes()));
+    }
+    // A counted entry lives outside the allocators until it's made immortal.
+    let hash = crate::hash::hash(b"promoted");
+    let (ptr, counted) = sc.try_insert_counted("promoted", hash).unwrap();
+    assert!(counted);
+    assert!(sc.memory_usage().counted_bytes > 0);
+    let strings = |sc: &StringCache| {
+        let mut ranges = Vec::new();
+        let len = sc.snapshot(&mut ranges);
+        let strings: Vec<&str> = ranges
+            .into_iter()
+            .flatten()
+            .map(|e| unsafe { ustr_from_entry(e) }.as_str())
+            .collect();
+        assert_eq!(strings.len(), len);
+        strings
+    };
+    assert_eq!(strings(&sc).len(), words.len());
+    assert_eq!(sc.insert("promoted", hash), ptr);
+    // Once promoted, it's no longer counted.
+    assert_eq!(sc.memory_usage().counted_bytes, 0);
+
+    let mut strings = strings(&sc);
+    strings.sort_unstable();
+    let mut expected: Vec<&str> = words.iter().map(String::as_str).collect();
+    expected.push("promoted");
+    expected.sort_unstable();
+    assert_eq!(strings, expected);
+
+    unsafe { sc.release() };
+}
+
+#[test]
+fn test_stats() {
+    // Small allocators that don't fit two strings leave a tail in each.
+    let mut sc = StringCache::new(8, 40, 1);
+    let words: Vec<String> = (0..1_000).map(|i| format!("w{i:03}")).collect();
+    for w in &words {
+        sc.insert(w, crate::hash::hash(w.as_bytes()));
+    }
+    let hash = crate::hash::hash(b"counted");
+    let (ptr, _) = sc.try_insert_counted("counted", hash).unwrap();
+
+    let stats = sc.stats();
+    assert_eq!(stats.entries, words.len() + 1);
+    assert!(stats.load_factor > 0.0 && stats.load_factor <= 0.5);
+    assert!(stats.max_probe_distance as f64 >= stats.mean_probe_distance);
+    // Every word is 4 bytes long, and "counted" 7.
+    assert_eq!(stats.length_histogram[3], words.len() + 1);
+    assert_eq!(stats.length_histogram.iter().sum::<usize>(), stats.entries);
+    // Each entry takes its header, 4 bytes and a null, padded to a multiple
+    // of 8, and only one fits in an allocator of 40 bytes.
+    let size = core::mem::size_of::<StringCacheEntry>() + 4 + 1;
+    let padded = size.next_multiple_of(8);
+    assert_eq!(stats.arenas, words.len());
+    assert_eq!(stats.padding_bytes, (padded - size) * words.len());
+    assert_eq!(stats.tail_bytes, (40 - padded) * (words.len() - 1));
+    assert!(stats.lock_acquisitions > words.len() as u64);
+    assert_eq!(stats.lock_contentions, 0);
+
+    // A thread that has to wait for the lock counts as contention, as long
+    // as it tries before the lock is let go.
+    #[cfg(not(feature = "single-thread"))]
+    for wait in 1..10 {
+        let guard = sc.lock();
+        std::thread::scope(|s| {
+            s.spawn(|| {
+                sc.insert("waiting", crate::hash::hash(b"waiting"));
+            });
+            std::thread::sleep(Duration::from_millis(10 << wait));
+            drop(guard);
+        });
+        if sc.stats().lock_contentions > 0 {
+            break;
+        }
+    }
+    #[cfg(not(feature = "single-thread"))]
+    assert_eq!(sc.stats().lock_contentions, 1);
+
+    let entry = unsafe { (ptr as *mut StringCacheEntry).sub(1) };
+    unsafe {
+        sc.lock().remove_counted(entry);
+        sc.release();
+    }
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_tracing() {
+    use std::sync::{Arc, Mutex};
+    use tracing::{Event, Metadata, span};
+
+    // Records the names of spans, and "event" for each event.
+    #[derive(Clone, Default)]
+    struct Names(Arc<Mutex<Vec<&'static str>>>);
+
+    impl tracing::Subscriber for Names {
+        fn enabled(&self, _: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, span: &span::Attributes<'_>) -> span::Id {
+            self.0.lock().unwrap().push(span.metadata().name());
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+        fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+        fn event(&self, _: &Event<'_>) {
+            self.0.lock().unwrap().push("event");
+        }
+        fn enter(&self, _: &span::Id) {}
+        fn exit(&self, _: &span::Id) {}
+    }
+
+    let names = Names::default();
+    let mut sc = StringCache::new(8, 256, 2);
+    tracing::subscriber::with_default(names.clone(), || {
+        for i in 0..1_000 {
+            let w = format!("word{i}");
+            sc.insert(&w, crate::hash::hash(w.as_bytes()));
+        }
+    });
+    let names = names.0.lock().unwrap();
+    let count = |name| names.iter().filter(|&&n| n == name).count();
+    assert!(count("grow_table") > 0);
+    assert!(count("rotate_arena") > 0);
+    // Each growth but maybe the last has finished.
+    assert!(count("event") >= count("grow_table") - 1);
+
+    unsafe { sc.release() };
+}
+
+#[test]
+fn test_large_strings() {
+    let hash = |s: &str| crate::hash::hash(s.as_bytes());
+    let mut sc = StringCache::new(8, 256, 2).large_string_threshold(100);
+    let small = sc.insert("small", hash("small"));
+    let capacity = sc.memory_usage().arena_capacity;
+
+    // Large strings leave the allocators alone.
+    let big = "x".repeat(1 << 20);
+    let ptr = sc.insert(&big, hash(&big));
+    assert_eq!(sc.insert(&big, hash(&big)), ptr);
+    assert_eq!(sc.get_existing(&big, hash(&big)), Some(ptr));
+    let exact = "y".repeat(100);
+    sc.insert(&exact, hash(&exact));
+    let usage = sc.memory_usage();
+    assert_eq!(usage.arena_capacity, capacity);
+    assert_eq!(usage.large_strings, 1);
+    assert_eq!(
+        usage.large_bytes,
+        core::mem::size_of::<StringCacheEntry>() + big.len() + 1
+    );
+    assert_eq!(
+        sc.total_allocated(),
+        usage.arena_allocated + usage.large_bytes
+    );
+    assert_eq!(sc.total_capacity(), capacity + usage.large_bytes);
+
+    // They are laid out like any other entry.
+    let u = unsafe { ustr_from_entry((ptr as *const StringCacheEntry).sub(1)) };
+    assert_eq!(u.as_str(), big);
+    assert_eq!(u.precomputed_hash(), hash(&big));
+    assert_eq!(u.as_cstr().to_bytes().len(), big.len());
+
+    let mut ranges = Vec::new();
+    assert_eq!(sc.snapshot(&mut ranges), 3);
+    let mut lens: Vec<usize> = ranges
+        .into_iter()
+        .flatten()
+        .map(|e| unsafe { ustr_from_entry(e) }.len())
+        .collect();
+    lens.sort_unstable();
+    assert_eq!(lens, [5, 100, 1 << 20]);
+    assert_eq!(sc.insert("small", hash("small")), small);
+
+    unsafe { sc.release() };
+    assert_eq!(sc.memory_usage().large_strings, 0);
+}
+
+#[cfg(all(feature = "mmap", unix))]
+#[test]
+fn test_mmap_arena() {
+    let hash = |s: &str| crate::hash::hash(s.as_bytes());
+    let mut sc = StringCache::new(8, 256, 2).mmap_arena(64 << 20, false);
+    assert_eq!(sc.memory_usage().arena_capacity, 0);
+
+    let words: Vec<String> = (0..100_000).map(|i| format!("mmap{i}")).collect();
+    let ptrs: Vec<*const u8> =
+        words.iter().map(|w| sc.insert(w, hash(w))).collect();
+    let usage = sc.memory_usage();
+    // Pages are committed as they're reached, all within the one range.
+    assert!(usage.arena_allocated <= usage.arena_capacity);
+    assert!(usage.arena_capacity < usage.arena_allocated + (64 << 10));
+    assert_eq!(sc.inner.lock().old_allocs.len(), 0);
+    for (w, &ptr) in words.iter().zip(&ptrs) {
+        assert_eq!(sc.get_existing(w, hash(w)), Some(ptr));
+    }
+    let mut ranges = Vec::new();
+    assert_eq!(sc.snapshot(&mut ranges), words.len());
+    assert_eq!(ranges.into_iter().flatten().count(), words.len());
+
+    // Once the range is full, the next one is reserved the same way.
+    let big = "z".repeat(8 << 10);
+    for i in 0..10_000 {
+        let s = format!("{big}{i}");
+        sc.insert(&s, hash(&s));
+    }
+    let usage = sc.memory_usage();
+    assert_eq!(sc.inner.lock().old_allocs.len(), 1);
+    assert!(usage.arena_capacity < usage.arena_allocated + (64 << 10) * 2);
+
+    unsafe { sc.clear() };
+    assert_eq!(sc.memory_usage().arena_capacity, 0);
+    sc.insert("again", hash("again"));
+    unsafe { sc.release() };
+
+    // With huge pages, whole huge pages are committed at a time.
+    // The range is aligned to huge pages, so the pages committed are too.
+    let mut sc = StringCache::new(8, 256, 2).mmap_arena(7 << 20, true);
+    let (_, end) = sc.inner.get_mut().alloc.allocated_range();
+    assert_eq!(sc.inner.get_mut().alloc.capacity(), 8 << 20);
+    assert_eq!(end as usize % (2 << 20), 0);
+    sc.insert("huge", hash("huge"));
+    assert_eq!(sc.memory_usage().arena_capacity, 2 << 20);
+    unsafe { sc.release() };
+
+    // Pages that can't be committed make the insert fail rather than abort.
+    // Mapping a file that's only open for reading over the range makes
+    // `mprotect()` refuse to make it writable.
+    use std::os::fd::AsRawFd;
+    let mut sc = StringCache::new(8, 256, 2).mmap_arena(1 << 20, false);
+    let zero = std::fs::File::open("/dev/zero").unwrap();
+    let (_, end) = sc.inner.get_mut().alloc.allocated_range();
+    let len = sc.inner.get_mut().alloc.capacity();
+    let mapped = unsafe {
+        libc::mmap(
+            end.sub(len) as *mut libc::c_void,
+            len,
+            libc::PROT_NONE,
+            libc::MAP_SHARED | libc::MAP_FIXED,
+            zero.as_raw_fd(),
+            0,
+        )
+    };
+    assert_ne!(mapped, libc::MAP_FAILED);
+    let mut locked = sc.lock();
+    assert_eq!(
+        locked.try_insert("uncommitted", hash("uncommitted")),
+        Err(InternError::OutOfMemory)
+    );
+    drop(locked);
+    assert_eq!(sc.num_entries(), 0);
+    assert_eq!(sc.memory_usage().arena_allocated, 0);
+    unsafe { sc.release() };
+}
+
+#[test]
+fn test_frozen_policy() {
+    let hash = |s: &str| crate::hash::hash(s.as_bytes());
+    let sc = StringCache::new(8, 256, 2).frozen_policy(FrozenPolicy::Panic);
+    let ptr = sc.insert("hello", hash("hello"));
+    sc.freeze();
+    assert_eq!(sc.try_insert("hello", hash("hello")), Ok(ptr));
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        sc.try_insert("world", hash("world"))
+    }));
+    assert!(res.is_err());
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        sc.try_insert_counted("world", hash("world"))
+    }));
+    assert!(res.is_err());
+    let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        sc.insert_batch([(0, "world", hash("world"))], |_, _| ())
+    }));
+    assert!(res.is_err());
+    // The panics happen after the lock is released, so it's free again.
+    assert!(sc.inner.try_lock().is_some());
+    assert_eq!(sc.get_existing("hello", hash("hello")), Some(ptr));
+    assert_eq!(sc.num_entries(), 1);
 }
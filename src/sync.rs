@@ -0,0 +1,101 @@
+// The locks and lazily initialized statics used by the global cache. With the
+// `std` feature they come from `parking_lot` and the standard library, which
+// park threads that have to wait. Without it there is nothing to park on, so
+// they spin.
+
+#[cfg(feature = "std")]
+pub(crate) use parking_lot::Mutex;
+#[cfg(feature = "std")]
+pub(crate) use std::sync::LazyLock as Lazy;
+
+#[cfg(not(feature = "std"))]
+pub(crate) use spin::{Lazy, Mutex};
+
+// The lock of each of the cache's bins. With the `single-thread` feature the
+// cache is never meant to be used by two threads at once, so the lock never
+// waits: taking it while another thread holds it panics instead.
+#[cfg(all(feature = "std", not(feature = "single-thread")))]
+pub(crate) use parking_lot::{Mutex as BinLock, MutexGuard as BinGuard};
+#[cfg(all(not(feature = "std"), not(feature = "single-thread")))]
+pub(crate) use spin::{Mutex as BinLock, MutexGuard as BinGuard};
+
+#[cfg(feature = "single-thread")]
+pub(crate) struct BinLock<T> {
+    locked: core::sync::atomic::AtomicBool,
+    value: core::cell::UnsafeCell<T>,
+}
+
+// The value is only ever reached through `get_mut()` or a guard, and there's
+// never more than one guard.
+#[cfg(feature = "single-thread")]
+unsafe impl<T: Send> Send for BinLock<T> {}
+#[cfg(feature = "single-thread")]
+unsafe impl<T: Send> Sync for BinLock<T> {}
+
+#[cfg(feature = "single-thread")]
+impl<T> BinLock<T> {
+    pub(crate) const fn new(value: T) -> BinLock<T> {
+        BinLock {
+            locked: core::sync::atomic::AtomicBool::new(false),
+            value: core::cell::UnsafeCell::new(value),
+        }
+    }
+
+    pub(crate) fn try_lock(&self) -> Option<BinGuard<'_, T>> {
+        match self
+            .locked
+            .swap(true, core::sync::atomic::Ordering::Acquire)
+        {
+            true => None,
+            false => Some(BinGuard { lock: self }),
+        }
+    }
+
+    #[track_caller]
+    pub(crate) fn lock(&self) -> BinGuard<'_, T> {
+        if self
+            .locked
+            .swap(true, core::sync::atomic::Ordering::Acquire)
+        {
+            panic!(
+                "the string cache was used by two threads at once, which the \
+                 `single-thread` feature of ustr doesn't allow"
+            );
+        }
+        BinGuard { lock: self }
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut T {
+        self.value.get_mut()
+    }
+}
+
+#[cfg(feature = "single-thread")]
+pub(crate) struct BinGuard<'a, T> {
+    lock: &'a BinLock<T>,
+}
+
+#[cfg(feature = "single-thread")]
+impl<T> core::ops::Deref for BinGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.value.get() }
+    }
+}
+
+#[cfg(feature = "single-thread")]
+impl<T> core::ops::DerefMut for BinGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.value.get() }
+    }
+}
+
+#[cfg(feature = "single-thread")]
+impl<T> Drop for BinGuard<'_, T> {
+    fn drop(&mut self) {
+        self.lock
+            .locked
+            .store(false, core::sync::atomic::Ordering::Release);
+    }
+}
@@ -0,0 +1,134 @@
+// Tags attributing the strings of the global cache to the code that
+// introduced them. The tag in effect on a thread is kept in a thread-local,
+// and each bin records the tag of every string it adds while one is set, see
+// `LockedCache::fill_slot()`. Handles don't carry tags.
+
+use crate::{STRING_CACHE, Ustr};
+use std::cell::Cell;
+
+thread_local! {
+    static CURRENT: Cell<Option<&'static str>> = const { Cell::new(None) };
+}
+
+// The tag strings added by this thread are recorded under.
+#[inline]
+pub(crate) fn current() -> Option<&'static str> {
+    CURRENT.with(Cell::get)
+}
+
+// Run `f` with `tag` as the current tag of this thread, then restore the one
+// before, even if `f` panics.
+pub(crate) fn with_current<R>(
+    tag: Option<&'static str>,
+    f: impl FnOnce() -> R,
+) -> R {
+    struct Restore(Option<&'static str>);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            CURRENT.with(|current| current.set(self.0));
+        }
+    }
+
+    let _restore = Restore(CURRENT.with(|current| current.replace(tag)));
+    f()
+}
+
+/// Run `f`, recording `tag` as the tag of every string it adds to the global
+/// cache from this thread.
+///
+/// A string keeps the tag it was introduced under for as long as it's in the
+/// cache: interning it again under another tag, or none, doesn't change it.
+/// Strings added without a tag aren't recorded. Tags nest, the innermost
+/// taking precedence, and [`par_intern()`](crate::par_intern) passes the tag
+/// on to the tasks it spawns. Recording a tag takes an entry in a side table
+/// of the string's bin, so it only costs memory for strings added under a
+/// tag.
+///
+/// See [`bytes_by_tag()`] for the memory used by the strings of each tag.
+/// When the global cache is owned by another copy of ustr through the
+/// `dylib` feature, strings are added by that copy and tags are ignored.
+///
+/// # Examples
+///
+/// ```
+/// use ustr::ustr;
+/// # unsafe { ustr::_clear_cache() };
+///
+/// let ident = ustr::with_tag("parser", || ustr("identifier"));
+/// assert_eq!(ident, "identifier");
+/// assert_eq!(ustr::cache::bytes_by_tag()[0].0, "parser");
+/// ```
+pub fn with_tag<R>(tag: &'static str, f: impl FnOnce() -> R) -> R {
+    with_current(Some(tag), f)
+}
+
+impl Ustr {
+    /// Create a new `Ustr` from the given `str` like [`Ustr::from`],
+    /// recording `tag` as its tag if it's new to the cache.
+    ///
+    /// This is the same as `ustr::with_tag(tag, || Ustr::from(string))`,
+    /// see [`with_tag()`].
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use ustr::Ustr;
+    /// # unsafe { ustr::_clear_cache() };
+    ///
+    /// Ustr::from_tagged("SELECT", "sql");
+    /// // The tag of a string is the first one it was interned under.
+    /// Ustr::from_tagged("SELECT", "http");
+    /// let tags = ustr::cache::bytes_by_tag();
+    /// assert_eq!(tags.len(), 1);
+    /// assert_eq!(tags[0].0, "sql");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if the string can't be added to the cache, like
+    /// [`Ustr::from`].
+    pub fn from_tagged(string: &str, tag: &'static str) -> Ustr {
+        with_tag(tag, || Ustr::from(string))
+    }
+}
+
+/// Returns the bytes taken by the strings of the global cache that were added
+/// under each tag, most bytes first.
+///
+/// Each string counts the full size of its entry: the string, its null
+/// terminator and the entry header. Strings added without a tag aren't
+/// counted, so the difference to [`total_allocated()`] is what untagged code
+/// added, plus padding.
+///
+/// [`total_allocated()`]: crate::total_allocated
+///
+/// # Examples
+///
+/// ```
+/// use ustr::{Ustr, ustr};
+/// # unsafe { ustr::_clear_cache() };
+///
+/// ustr::with_tag("config", || {
+///     ustr("timeout");
+///     ustr("retries");
+/// });
+/// Ustr::from_tagged(
+///     "connection reset by peer while reading the response",
+///     "logging",
+/// );
+///
+/// let tags = ustr::cache::bytes_by_tag();
+/// assert_eq!(tags[0].0, "logging");
+/// assert_eq!(tags[1].0, "config");
+/// assert!(tags[0].1 > tags[1].1);
+/// ```
+pub fn bytes_by_tag() -> Vec<(&'static str, usize)> {
+    let mut bytes = std::collections::HashMap::new();
+    for sc in STRING_CACHE.0.iter() {
+        sc.tag_bytes(&mut bytes);
+    }
+    let mut bytes: Vec<_> = bytes.into_iter().collect();
+    bytes.sort_unstable_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    bytes
+}
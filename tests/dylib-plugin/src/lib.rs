@@ -0,0 +1,41 @@
+use std::{os::raw::c_char, slice, str};
+use ustr::{ArcUstr, Ustr};
+
+unsafe fn as_str<'a>(ptr: *const u8, len: usize) -> &'a str {
+    unsafe { str::from_utf8_unchecked(slice::from_raw_parts(ptr, len)) }
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn plugin_intern(
+    ptr: *const u8,
+    len: usize,
+) -> *const c_char {
+    ustr::ustr(unsafe { as_str(ptr, len) }).as_char_ptr()
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn plugin_existing(
+    ptr: *const u8,
+    len: usize,
+) -> *const c_char {
+    Ustr::from_existing(unsafe { as_str(ptr, len) })
+        .map_or(std::ptr::null(), |u| u.as_char_ptr())
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn plugin_arc(
+    ptr: *const u8,
+    len: usize,
+) -> *mut ArcUstr {
+    Box::into_raw(Box::new(ArcUstr::from(unsafe { as_str(ptr, len) })))
+}
+
+#[unsafe(no_mangle)]
+pub unsafe extern "C" fn plugin_drop_arc(arc: *mut ArcUstr) {
+    drop(unsafe { Box::from_raw(arc) });
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn plugin_num_entries() -> usize {
+    ustr::num_entries()
+}